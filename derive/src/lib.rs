@@ -0,0 +1,90 @@
+//! Derive macros for [crunch_eval](https://docs.rs/crunch_eval)
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, LitStr};
+
+/// Implement `crunch_eval::vars::VarSource` for a struct with named fields, so that each field
+/// is a variable with the same name. Field values are converted to the number type with `Into`.
+///
+/// Use `#[expr(rename = "name")]` on a field to give its variable a different name, and
+/// `#[expr(skip)]` to leave it out.
+#[proc_macro_derive(ExprVars, attributes(expr))]
+pub fn derive_expr_vars(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "ExprVars requires a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "ExprVars can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut arms = Vec::new();
+    let mut bounds = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("named field");
+        let mut name = ident.to_string();
+        let mut skip = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("expr")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else if meta.path.is_ident("rename") {
+                    name = meta.value()?.parse::<LitStr>()?.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip` or `rename = \"...\"`"))
+                }
+            })?;
+        }
+        if skip {
+            continue;
+        }
+        let ty = &field.ty;
+        arms.push(quote! {
+            #name => ::core::option::Option::Some(::core::convert::Into::into(
+                ::core::clone::Clone::clone(&self.#ident),
+            )),
+        });
+        bounds.push(quote! { #ty: ::core::clone::Clone + ::core::convert::Into<__T> });
+    }
+
+    let ident = &input.ident;
+    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+    let predicates = where_clause.map(|w| &w.predicates);
+    let mut generics = input.generics.clone();
+    generics.params.push(parse_quote!(__T));
+    let (impl_generics, _, _) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::crunch_eval::vars::VarSource<__T> for #ident #ty_generics
+        where
+            #(#bounds,)*
+            #predicates
+        {
+            fn var(&self, name: &str) -> ::core::option::Option<__T> {
+                match name {
+                    #(#arms)*
+                    _ => ::core::option::Option::None,
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,373 @@
+use std::fmt::{self, Debug, Display};
+
+use crate::{Number, Value};
+
+/// A binary operator. More may be added, so matches on it need a wildcard arm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+impl BinaryOp {
+    /// The operator as written in source
+    pub fn symbol(self) -> &'static str {
+        use BinaryOp::*;
+        match self {
+            Or => "||",
+            And => "&&",
+            Eq => "==",
+            Ne => "!=",
+            Lt => "<",
+            Le => "<=",
+            Gt => ">",
+            Ge => ">=",
+            BitOr => "|",
+            BitXor => "xor",
+            BitAnd => "&",
+            Shl => "<<",
+            Shr => ">>",
+            Add => "+",
+            Sub => "-",
+            Mul => "*",
+            Div => "/",
+            Rem => "%",
+            Pow => "^",
+        }
+    }
+
+    /// How tightly the operator binds, where higher binds tighter. Prefix operators bind with
+    /// [`UnaryOp::PRECEDENCE`], tighter than every binary operator except `^`.
+    pub fn precedence(self) -> usize {
+        use BinaryOp::*;
+        match self {
+            Or => 0,
+            And => 1,
+            Eq | Ne => 2,
+            Lt | Le | Gt | Ge => 3,
+            BitOr => 4,
+            BitXor => 5,
+            BitAnd => 6,
+            Shl | Shr => 7,
+            Add | Sub => 8,
+            Mul | Div | Rem => 9,
+            Pow => 11,
+        }
+    }
+
+    /// Whether a chain like `a ^ b ^ c` groups from the right
+    pub fn is_right_assoc(self) -> bool {
+        self == BinaryOp::Pow
+    }
+
+    /// Whether the right operand is only evaluated if the left doesn't decide the result, as
+    /// for `&&` and `||`
+    pub fn short_circuits(self) -> bool {
+        matches!(self, BinaryOp::And | BinaryOp::Or)
+    }
+
+    /// The result decided by the truth of the left operand alone, if the operator short-circuits
+    pub(crate) fn short_circuit(self, left: bool) -> Option<bool> {
+        match (self, left) {
+            (BinaryOp::And, false) => Some(false),
+            (BinaryOp::Or, true) => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// A prefix operator. More may be added, so matches on it need a wildcard arm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+impl UnaryOp {
+    pub const PRECEDENCE: usize = 10;
+
+    /// The operator as written in source
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+            UnaryOp::BitNot => "~",
+        }
+    }
+}
+
+/// A node of a compiled expression's syntax tree. More kinds of node may be added, so matches on
+/// it need a wildcard arm, and [`Visitor`] and [`Fold`] are the stable ways to walk a tree.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Node<T> {
+    Constant(T),
+    /// A variable, by its index in the environment and its name
+    Variable(usize, String),
+    Binary(BinaryOp, Box<[Node<T>; 2]>),
+    Unary(UnaryOp, Box<Node<T>>),
+    /// A call to a function registered in the environment, by its registered name
+    Call(String, Vec<Node<T>>),
+    /// Condition, value if true, value if false
+    Conditional(Box<[Node<T>; 3]>),
+}
+
+impl<T: Debug> Node<T> {
+    /// Precedence of the node's outermost operator, or `usize::MAX` if it has none
+    fn precedence(&self) -> usize {
+        match self {
+            Node::Binary(op, _) => op.precedence(),
+            _ if self.is_prefix() => UnaryOp::PRECEDENCE,
+            _ => usize::MAX,
+        }
+    }
+
+    /// Whether the node is written starting with a prefix operator, including negative constants
+    fn is_prefix(&self) -> bool {
+        match self {
+            Node::Unary(_, _) => true,
+            Node::Constant(value) => format!("{value:?}").starts_with('-'),
+            _ => false,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, parens: bool) -> fmt::Result {
+        if parens {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+/// Prints the node as source text, with only the parentheses needed to keep its structure when
+/// compiled again. Conditionals are printed as `if(condition, a, b)`. Constants are printed with
+/// `Debug`, so non-finite floats only compile again if `inf` and `NaN` are defined.
+impl<T: Debug> Display for Node<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Constant(value) => write!(f, "{value:?}"),
+            Node::Variable(_, name) => f.write_str(name),
+            Node::Binary(op, args) => {
+                let [left, right] = &**args;
+                let precedence = op.precedence();
+                let left_parens = left.precedence() < precedence
+                    || (left.precedence() == precedence && op.is_right_assoc());
+                // A prefix operator applies to everything after it that binds tighter, so it never
+                // needs parentheses on the right
+                let right_parens = !right.is_prefix()
+                    && (right.precedence() < precedence
+                        || (right.precedence() == precedence && !op.is_right_assoc()));
+                left.fmt_operand(f, left_parens)?;
+                write!(f, " {} ", op.symbol())?;
+                right.fmt_operand(f, right_parens)
+            }
+            Node::Unary(op, arg) => {
+                f.write_str(op.symbol())?;
+                arg.fmt_operand(f, arg.precedence() < UnaryOp::PRECEDENCE)
+            }
+            Node::Call(name, args) => {
+                write!(f, "{name}(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+                f.write_str(")")
+            }
+            Node::Conditional(args) => {
+                let [condition, if_true, if_false] = &**args;
+                write!(f, "if({condition}, {if_true}, {if_false})")
+            }
+        }
+    }
+}
+
+/// Walks a [`Node`] tree by reference. Each method's default visits the node's children, so an
+/// implementation only needs to override the kinds of node it is interested in. Kinds of node
+/// added later come with defaults of their own.
+///
+/// Example:
+/// ```
+/// use crunch_eval::{ast::{BinaryOp, Node, Visitor}, env::ExprEnv, expr::Expr};
+///
+/// struct CountDivisions(usize);
+///
+/// impl Visitor<f64> for CountDivisions {
+///     fn visit_binary(&mut self, op: BinaryOp, left: &Node<f64>, right: &Node<f64>) {
+///         if op == BinaryOp::Div {
+///             self.0 += 1;
+///         }
+///         self.visit(left);
+///         self.visit(right);
+///     }
+/// }
+///
+/// let expr = Expr::compile_env("x / 2 + 1 / (x / 3)", ExprEnv::new(["x"])).unwrap();
+/// let mut count = CountDivisions(0);
+/// count.visit(&expr.ast());
+/// assert_eq!(count.0, 3);
+/// ```
+pub trait Visitor<T> {
+    fn visit(&mut self, node: &Node<T>) {
+        match node {
+            Node::Constant(value) => self.visit_constant(value),
+            Node::Variable(index, name) => self.visit_variable(*index, name),
+            Node::Binary(op, args) => self.visit_binary(*op, &args[0], &args[1]),
+            Node::Unary(op, arg) => self.visit_unary(*op, arg),
+            Node::Call(name, args) => self.visit_call(name, args),
+            Node::Conditional(args) => self.visit_conditional(&args[0], &args[1], &args[2]),
+        }
+    }
+
+    fn visit_constant(&mut self, _value: &T) {}
+
+    fn visit_variable(&mut self, _index: usize, _name: &str) {}
+
+    fn visit_binary(&mut self, _op: BinaryOp, left: &Node<T>, right: &Node<T>) {
+        self.visit(left);
+        self.visit(right);
+    }
+
+    fn visit_unary(&mut self, _op: UnaryOp, arg: &Node<T>) {
+        self.visit(arg);
+    }
+
+    fn visit_call(&mut self, _name: &str, args: &[Node<T>]) {
+        for arg in args {
+            self.visit(arg);
+        }
+    }
+
+    fn visit_conditional(&mut self, condition: &Node<T>, if_true: &Node<T>, if_false: &Node<T>) {
+        self.visit(condition);
+        self.visit(if_true);
+        self.visit(if_false);
+    }
+}
+
+/// Rebuilds a [`Node`] tree by value. Each method's default rebuilds the node from its folded
+/// children, so an implementation only needs to override the kinds of node it rewrites, and
+/// kinds of node added later come with defaults of their own. The result can be compiled again
+/// with [`Expr::from_ast`](crate::expr::Expr::from_ast).
+///
+/// Example:
+/// ```
+/// use crunch_eval::{ast::{Fold, Node}, env::ExprEnv, expr::Expr};
+///
+/// struct Substitute(f64);
+///
+/// impl Fold<f64> for Substitute {
+///     fn fold_variable(&mut self, _index: usize, _name: String) -> Node<f64> {
+///         Node::Constant(self.0)
+///     }
+/// }
+///
+/// let expr = Expr::compile_env("-x", ExprEnv::new(["x"])).unwrap();
+/// let folded = Substitute(2.0).fold(expr.ast());
+/// assert_eq!(folded, Expr::<f64, 0>::compile("-2").unwrap().ast());
+/// ```
+pub trait Fold<T> {
+    fn fold(&mut self, node: Node<T>) -> Node<T> {
+        match node {
+            Node::Constant(value) => self.fold_constant(value),
+            Node::Variable(index, name) => self.fold_variable(index, name),
+            Node::Binary(op, args) => {
+                let [left, right] = *args;
+                self.fold_binary(op, left, right)
+            }
+            Node::Unary(op, arg) => self.fold_unary(op, *arg),
+            Node::Call(name, args) => self.fold_call(name, args),
+            Node::Conditional(args) => {
+                let [condition, if_true, if_false] = *args;
+                self.fold_conditional(condition, if_true, if_false)
+            }
+        }
+    }
+
+    fn fold_constant(&mut self, value: T) -> Node<T> {
+        Node::Constant(value)
+    }
+
+    fn fold_variable(&mut self, index: usize, name: String) -> Node<T> {
+        Node::Variable(index, name)
+    }
+
+    fn fold_binary(&mut self, op: BinaryOp, left: Node<T>, right: Node<T>) -> Node<T> {
+        Node::Binary(op, Box::new([self.fold(left), self.fold(right)]))
+    }
+
+    fn fold_unary(&mut self, op: UnaryOp, arg: Node<T>) -> Node<T> {
+        Node::Unary(op, Box::new(self.fold(arg)))
+    }
+
+    fn fold_call(&mut self, name: String, args: Vec<Node<T>>) -> Node<T> {
+        Node::Call(name, args.into_iter().map(|arg| self.fold(arg)).collect())
+    }
+
+    fn fold_conditional(
+        &mut self,
+        condition: Node<T>,
+        if_true: Node<T>,
+        if_false: Node<T>,
+    ) -> Node<T> {
+        Node::Conditional(Box::new([
+            self.fold(condition),
+            self.fold(if_true),
+            self.fold(if_false),
+        ]))
+    }
+}
+
+impl<T: Number> Value<T> {
+    /// Convert to a syntax tree, naming variables from the environment's variable names
+    pub(crate) fn to_node(&self, var_names: &[String]) -> Node<T> {
+        let to_nodes = |values: &[Value<T>]| values.iter().map(|v| v.to_node(var_names)).collect();
+        match self {
+            Value::Constant(value) => Node::Constant(*value),
+            Value::Variable(index) => Node::Variable(*index, var_names[*index].clone()),
+            Value::BinaryOperation(op, args, _) | Value::ShortCircuit(op, args, _) => {
+                let [left, right] = &**args;
+                Node::Binary(
+                    op.op,
+                    Box::new([left.to_node(var_names), right.to_node(var_names)]),
+                )
+            }
+            Value::UnaryOperation(op, arg, _) => {
+                Node::Unary(op.op, Box::new(arg.to_node(var_names)))
+            }
+            Value::FunctionInvoke(invoke) => {
+                Node::Call(invoke.func.name.to_string(), to_nodes(&invoke.args))
+            }
+            Value::Conditional(args) => {
+                let [condition, if_true, if_false] = &**args;
+                Node::Conditional(Box::new([
+                    condition.to_node(var_names),
+                    if_true.to_node(var_names),
+                    if_false.to_node(var_names),
+                ]))
+            }
+        }
+    }
+}
@@ -3,57 +3,200 @@ use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ops::DerefMut;
 
+use crate::ast::{BinaryOp, Node, UnaryOp};
 use crate::env::ExprEnv;
 use crate::func::Function;
 use crate::func::FunctionInvoke;
-use crate::{parser::*, EvalError, Number, Value};
-
-fn get_operator<T: Number, const N: usize>(c: char) -> Option<Token<T, N>> {
-    use Token::*;
-    match c {
-        '+' => Some(BinaryOperator(0, |a, b| {
-            a.add(b).ok_or(EvalError::Overflow)
-        })),
-        '-' => Some(BinaryOperator(0, |a, b| {
-            a.sub(b).ok_or(EvalError::Overflow)
-        })),
-        '*' => Some(BinaryOperator(1, |a, b| {
-            a.mul(b).ok_or(EvalError::Overflow)
-        })),
-        '/' => Some(BinaryOperator(1, |a, b| {
-            a.div(b).ok_or(EvalError::DivideByZero)
-        })),
-        '%' => Some(BinaryOperator(1, |a, b| {
-            a.rem(b).ok_or(EvalError::DivideByZero)
-        })),
-        '^' => Some(BinaryOperator(2, |a, b| a.pow(b))),
-        _ => None,
-    }
+use crate::lexer::{lex, LexemeKind};
+use crate::number::{finite, parse_literal, FloatMode, IntegerMode, LiteralError};
+use crate::{parser::*, BinaryFn, EvalError, Number, Operator, UnaryFn, Value};
+
+fn get_operator<T: Number>(op: &str, mode: IntegerMode) -> Option<Token<T>> {
+    use IntegerMode::*;
+    Some(match op {
+        "||" => binary(BinaryOp::Or, |a, b| {
+            Ok(T::from_bool(a.to_bool() || b.to_bool()))
+        }),
+        "&&" => binary(BinaryOp::And, |a, b| {
+            Ok(T::from_bool(a.to_bool() && b.to_bool()))
+        }),
+        "==" => binary(BinaryOp::Eq, |a, b| Ok(T::from_bool(a == b))),
+        "!=" => binary(BinaryOp::Ne, |a, b| Ok(T::from_bool(a != b))),
+        "<" => binary(BinaryOp::Lt, |a, b| Ok(T::from_bool(a < b))),
+        "<=" => binary(BinaryOp::Le, |a, b| Ok(T::from_bool(a <= b))),
+        ">" => binary(BinaryOp::Gt, |a, b| Ok(T::from_bool(a > b))),
+        ">=" => binary(BinaryOp::Ge, |a, b| Ok(T::from_bool(a >= b))),
+        "+" => binary(
+            BinaryOp::Add,
+            match mode {
+                Checked => |a, b| a.add(b).ok_or(EvalError::Overflow),
+                Wrapping => |a, b| Ok(a.wrapping_add(b)),
+                Saturating => |a, b| Ok(a.saturating_add(b)),
+            },
+        ),
+        "-" => binary(
+            BinaryOp::Sub,
+            match mode {
+                Checked => |a, b| a.sub(b).ok_or(EvalError::Overflow),
+                Wrapping => |a, b| Ok(a.wrapping_sub(b)),
+                Saturating => |a, b| Ok(a.saturating_sub(b)),
+            },
+        ),
+        "*" => binary(
+            BinaryOp::Mul,
+            match mode {
+                Checked => |a, b| a.mul(b).ok_or(EvalError::Overflow),
+                Wrapping => |a, b| Ok(a.wrapping_mul(b)),
+                Saturating => |a, b| Ok(a.saturating_mul(b)),
+            },
+        ),
+        // Division only overflows for `MIN / -1`, so zero must be checked for separately
+        "/" => binary(
+            BinaryOp::Div,
+            match mode {
+                Checked => |a, b| match a.div(b) {
+                    None if b == T::default() => Err(EvalError::DivideByZero),
+                    None => Err(EvalError::Overflow),
+                    Some(val) => Ok(val),
+                },
+                Wrapping => |a, b| a.wrapping_div(b).ok_or(EvalError::DivideByZero),
+                Saturating => |a, b| a.saturating_div(b).ok_or(EvalError::DivideByZero),
+            },
+        ),
+        "%" => binary(
+            BinaryOp::Rem,
+            match mode {
+                Checked => |a, b| match a.rem(b) {
+                    None if b == T::default() => Err(EvalError::DivideByZero),
+                    None => Err(EvalError::Overflow),
+                    Some(val) => Ok(val),
+                },
+                Wrapping => |a, b| a.wrapping_rem(b).ok_or(EvalError::DivideByZero),
+                Saturating => |a, b| a.saturating_rem(b).ok_or(EvalError::DivideByZero),
+            },
+        ),
+        "^" => binary(
+            BinaryOp::Pow,
+            match mode {
+                Checked => |a, b| a.pow(b),
+                Wrapping => |a, b| a.wrapping_pow(b),
+                Saturating => |a, b| a.saturating_pow(b),
+            },
+        ),
+        _ => return None,
+    })
 }
 
-#[derive(Clone, Debug)]
-pub(crate) enum Token<T: Number, const N: usize> {
-    Value(Value<T, N>),
-    BinaryOperator(usize, fn(T, T) -> Result<T, EvalError>),
-    Function(Function<T, N>),
+/// Operators which behave differently for floats under the given mode, overriding the built-in ones
+fn get_float_operator<T: Number>(op: &str, mode: FloatMode) -> Option<Token<T>> {
+    Some(match (mode, op) {
+        (FloatMode::Standard, _) => return None,
+        (FloatMode::Strict, "+") => binary(BinaryOp::Add, |a, b| {
+            finite(a.add(b).ok_or(EvalError::Overflow)?)
+        }),
+        (FloatMode::Strict, "-") => binary(BinaryOp::Sub, |a, b| {
+            finite(a.sub(b).ok_or(EvalError::Overflow)?)
+        }),
+        (FloatMode::Strict, "*") => binary(BinaryOp::Mul, |a, b| {
+            finite(a.mul(b).ok_or(EvalError::Overflow)?)
+        }),
+        (FloatMode::Strict, "/") => binary(BinaryOp::Div, |a, b| {
+            finite(a.div(b).ok_or(EvalError::DivideByZero)?)
+        }),
+        (FloatMode::Strict, "%") => binary(BinaryOp::Rem, |a, b| {
+            finite(a.rem(b).ok_or(EvalError::DivideByZero)?)
+        }),
+        (FloatMode::Strict, "^") => binary(BinaryOp::Pow, |a, b| finite(a.pow(b)?)),
+        (FloatMode::Ieee, "/") => binary(BinaryOp::Div, |a, b| {
+            a.ieee_div(b).ok_or(EvalError::DivideByZero)
+        }),
+        (FloatMode::Ieee, "%") => binary(BinaryOp::Rem, |a, b| {
+            a.ieee_rem(b).ok_or(EvalError::DivideByZero)
+        }),
+        _ => return None,
+    })
 }
 
-impl<T: Number, const N: usize> Token<T, N> {
-    fn is_operator(&self) -> bool {
-        matches!(self, Self::BinaryOperator(_, _))
-    }
+fn get_unary_operator<T: Number>(c: char, mode: IntegerMode) -> Option<Token<T>> {
+    Some(match c {
+        '-' => unary(
+            UnaryOp::Neg,
+            match mode {
+                IntegerMode::Checked => |x| x.neg().ok_or(EvalError::Overflow),
+                IntegerMode::Wrapping => |x| Ok(x.wrapping_neg()),
+                IntegerMode::Saturating => |x| Ok(x.saturating_neg()),
+            },
+        ),
+        '!' => unary(UnaryOp::Not, |x| Ok(T::from_bool(!x.to_bool()))),
+        _ => return None,
+    })
+}
+
+/// The integer and float modes an expression was compiled with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Modes {
+    pub integer: IntegerMode,
+    pub float: FloatMode,
+}
+
+/// Find a built-in binary operator by its symbol, as it behaves under the given modes
+pub(crate) fn builtin_binary<T: Number>(symbol: &str, modes: Modes) -> Option<Token<T>> {
+    get_float_operator(symbol, modes.float).or_else(|| get_operator(symbol, modes.integer))
+}
+
+/// Find a built-in prefix operator by its symbol, as it behaves under the given modes
+pub(crate) fn builtin_unary<T: Number>(symbol: char, modes: Modes) -> Option<Token<T>> {
+    get_unary_operator(symbol, modes.integer)
+}
+
+pub(crate) fn binary<T: Number>(op: BinaryOp, func: BinaryFn<T>) -> Token<T> {
+    let assoc = if op.is_right_assoc() {
+        Assoc::Right
+    } else {
+        Assoc::Left
+    };
+    Token::BinaryOperator(op.precedence(), assoc, Operator { op, func })
+}
+
+pub(crate) fn unary<T: Number>(op: UnaryOp, func: UnaryFn<T>) -> Token<T> {
+    Token::UnaryOperator(UnaryOp::PRECEDENCE, Operator { op, func })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Assoc {
+    Left,
+    Right,
+}
 
+type SpannedToken<T> = (Token<T>, Span);
+type TokenStack<T> = VecDeque<SpannedToken<T>>;
+
+#[derive(Clone, Debug)]
+pub(crate) enum Token<T: Number> {
+    Value(Value<T>),
+    BinaryOperator(usize, Assoc, Operator<BinaryOp, BinaryFn<T>>),
+    UnaryOperator(usize, Operator<UnaryOp, UnaryFn<T>>),
+    Function(Function<T>),
+}
+
+impl<T: Number> Token<T> {
     fn get_priority(&self) -> usize {
         match self {
-            Self::BinaryOperator(priority, _) => *priority,
+            Self::BinaryOperator(priority, _, _) | Self::UnaryOperator(priority, _) => *priority,
             _ => 0,
         }
     }
+
+    /// Whether this operator binds tighter than an incoming binary operator
+    fn precedes(&self, priority: usize, assoc: Assoc) -> bool {
+        let own = self.get_priority();
+        own > priority || (own == priority && assoc == Assoc::Left)
+    }
 }
 
 pub(crate) struct ExpressionCompiler<'a, T: Number, const N: usize> {
     parser: ParserState<'a>,
-    env: ExprEnv<T, N>,
+    env: &'a ExprEnv<T, N>,
     num_type: PhantomData<T>,
 }
 
@@ -72,13 +215,20 @@ impl<'a, T: Number, const N: usize> DerefMut for ExpressionCompiler<'a, T, N> {
 }
 
 impl<'a, T: Number, const N: usize> ExpressionCompiler<'a, T, N> {
-    pub fn compile(s: impl Into<String>, env: ExprEnv<T, N>) -> Result<Value<T, N>, ParserError> {
+    pub fn compile(s: impl Into<String>, env: &ExprEnv<T, N>) -> Result<Value<T>, ParserError> {
         let string: String = s.into();
-        let chars: Vec<char> = string.chars().filter(|c| !c.is_whitespace()).collect();
+        let lexemes = lex(&string);
+        if lexemes.is_empty() {
+            return Err(ParserError::EmptyInput(Span {
+                start: 0,
+                end: string.len(),
+            }));
+        }
         let mut compiler = ExpressionCompiler {
             parser: ParserState {
-                source: &chars,
+                source: &lexemes,
                 pos: 0,
+                len: string.len(),
             },
             env,
             num_type: Default::default(),
@@ -86,133 +236,361 @@ impl<'a, T: Number, const N: usize> ExpressionCompiler<'a, T, N> {
         compiler.parse_expression(None)
     }
 
-    fn parse_expression(&mut self, terminator: Option<char>) -> Result<Value<T, N>, ParserError> {
-        let mut tokens = Vec::new();
-        tokens.push(self.parse_term_neg()?);
-        while self.peek().is_some() && self.peek() != terminator {
-            let op = self.parse_binary_operator()?;
-            tokens.push(op);
-            tokens.push(self.parse_term_neg()?);
-        }
-        if self.peek() != terminator {
+    fn parse_expression(&mut self, terminator: Option<char>) -> Result<Value<T>, ParserError> {
+        let value = self.parse_conditional()?;
+        if !self.at_terminator(terminator) {
             return Err(ParserError::ExpectedChar(
-                self.pos,
+                self.span(),
                 terminator.unwrap_or_default(),
             ));
         }
         self.pos += 1;
+        Ok(value)
+    }
+
+    /// Whether the next lexeme is the given terminator, where `None` stands for the end of input
+    fn at_terminator(&self, terminator: Option<char>) -> bool {
+        match terminator {
+            Some(c) => self.peek_char() == Some(c),
+            None => self.peek().is_none(),
+        }
+    }
+
+    fn parse_conditional(&mut self) -> Result<Value<T>, ParserError> {
+        let condition = self.parse_operation()?;
+        if !self.check_char('?') {
+            return Ok(condition);
+        }
+        let if_true = self.parse_expression(Some(':'))?;
+        let if_false = self.parse_conditional()?;
+        Ok(Value::Conditional(Box::new([condition, if_true, if_false])))
+    }
+
+    fn parse_operation(&mut self) -> Result<Value<T>, ParserError> {
+        let mut tokens = Vec::new();
+        self.parse_operand(&mut tokens)?;
+        while self.peek().is_some() && !matches!(self.peek_char(), Some('?' | ':' | ',' | ')')) {
+            let span = self.span();
+            let op = self.parse_binary_operator()?;
+            tokens.push((op, span));
+            self.parse_operand(&mut tokens)?;
+        }
         let stack = Self::shunting_yard(tokens)?;
-        Self::reduce_stack(stack)
+        Self::reduce_stack(stack, self.span())
     }
 
-    fn shunting_yard(tokens: Vec<Token<T, N>>) -> Result<VecDeque<Token<T, N>>, ParserError> {
+    fn shunting_yard(tokens: Vec<SpannedToken<T>>) -> Result<TokenStack<T>, ParserError> {
         let mut ops = VecDeque::new();
         let mut stack = VecDeque::new();
         for token in tokens {
-            if token.is_operator() {
-                while ops.back().map_or(false, |op: &Token<T, N>| {
-                    token.get_priority() <= op.get_priority()
-                }) {
-                    stack.push_back(ops.pop_back().unwrap());
+            match token.0 {
+                Token::BinaryOperator(priority, assoc, _) => {
+                    while ops
+                        .back()
+                        .is_some_and(|(op, _): &SpannedToken<T>| op.precedes(priority, assoc))
+                    {
+                        stack.push_back(ops.pop_back().unwrap());
+                    }
+                    ops.push_back(token);
                 }
-                ops.push_back(token);
-            } else {
-                stack.push_back(token);
+                // Prefix operators apply to the operand that follows, so they never pop
+                Token::UnaryOperator(_, _) => ops.push_back(token),
+                _ => stack.push_back(token),
             }
         }
         stack.extend(ops.into_iter().rev());
         Ok(stack)
     }
 
-    fn reduce_stack(mut stack: VecDeque<Token<T, N>>) -> Result<Value<T, N>, ParserError> {
-        let value = Self::compile_value(&mut stack)?;
-        if stack.is_empty() {
-            Ok(value)
-        } else {
-            Err(ParserError::DanglingValue)
+    /// Compile the stack into a single value, where `end` is the span following the operation
+    fn reduce_stack(mut stack: TokenStack<T>, end: Span) -> Result<Value<T>, ParserError> {
+        let (value, _) = Self::compile_value(&mut stack, end)?;
+        match stack.back() {
+            None => Ok(value),
+            Some((_, span)) => Err(ParserError::DanglingValue(*span)),
         }
     }
 
-    fn compile_value(stack: &mut VecDeque<Token<T, N>>) -> Result<Value<T, N>, ParserError> {
+    /// Pop a value off the stack, along with the span of source it was compiled from. `at` is the
+    /// span of the operator needing the value, where an error is reported if there is none.
+    fn compile_value(stack: &mut TokenStack<T>, at: Span) -> Result<(Value<T>, Span), ParserError> {
         match stack.pop_back() {
-            Some(Token::Value(val)) => Ok(val),
-            Some(Token::BinaryOperator(_, op)) => {
-                let right = Self::compile_value(stack)?;
-                let left = Self::compile_value(stack)?;
-                Ok(Value::BinaryOperation(op, Box::new([left, right])))
+            Some((Token::Value(val), span)) => Ok((val, span)),
+            Some((Token::BinaryOperator(_, _, op), op_span)) => {
+                let (right, right_span) = Self::compile_value(stack, op_span)?;
+                let (left, left_span) = Self::compile_value(stack, op_span)?;
+                let span = left_span.to(right_span);
+                Ok((Value::binary(op, Box::new([left, right]), span), span))
+            }
+            Some((Token::UnaryOperator(_, op), op_span)) => {
+                let (arg, arg_span) = Self::compile_value(stack, op_span)?;
+                let span = op_span.to(arg_span);
+                Ok((Value::UnaryOperation(op, Box::new(arg), span), span))
             }
-            _ => Err(ParserError::NoValue),
+            Some((_, span)) => Err(ParserError::NoValue(span)),
+            None => Err(ParserError::NoValue(at)),
         }
     }
 
-    fn parse_name(&mut self) -> Result<String, ParserError> {
-        self.take_while("name", |c| c.is_alphabetic())
+    fn parse_name(&mut self) -> Result<&'a str, ParserError> {
+        self.take("name", LexemeKind::Name)
     }
 
     fn parse_number(&mut self) -> Result<T, ParserError> {
-        self.take_while("number", |c| c.is_ascii_digit() || c == '.')?
-            .parse()
-            .map_err(|_| ParserError::ExpectedToken(self.pos, "number"))
+        let span = self.span();
+        let text = self.take("number", LexemeKind::Number)?;
+        let type_name = std::any::type_name::<T>();
+        let value = parse_literal(text, false).map_err(|e| match e {
+            LiteralError::Invalid => ParserError::InvalidLiteral(span, text.into(), type_name),
+            LiteralError::OutOfRange => {
+                ParserError::LiteralOutOfRange(span, text.into(), type_name)
+            }
+        })?;
+        if !accepts_constant(self.env, value) {
+            return Err(ParserError::LiteralOutOfRange(span, text.into(), type_name));
+        }
+        Ok(value)
     }
 
-    fn parse_binary_operator(&mut self) -> Result<Token<T, N>, ParserError> {
+    fn parse_binary_operator(&mut self) -> Result<Token<T>, ParserError> {
+        let span = self.span();
         self.advance()
-            .and_then(get_operator)
-            .ok_or(ParserError::ExpectedToken(self.pos, "operator"))
+            .filter(|l| l.kind != LexemeKind::Number)
+            .and_then(|l| {
+                builtin_binary(l.text, self.env.modes())
+                    .or_else(|| self.env.binary_operator(l.text))
+            })
+            .ok_or(ParserError::ExpectedToken(span, "operator"))
     }
 
-    fn parse_term_neg(&mut self) -> Result<Token<T, N>, ParserError> {
-        let negative = self.check_char('-');
+    fn parse_unary_operator(&mut self) -> Option<Token<T>> {
+        let op = self.peek_char().and_then(|c| {
+            builtin_unary(c, self.env.modes())
+                .or_else(|| self.env.unary_operator(c.encode_utf8(&mut [0; 4])))
+        })?;
+        self.pos += 1;
+        Some(op)
+    }
+
+    fn parse_operand(&mut self, tokens: &mut Vec<SpannedToken<T>>) -> Result<(), ParserError> {
+        loop {
+            let span = self.span();
+            let Some(op) = self.parse_unary_operator() else {
+                break;
+            };
+            tokens.push((op, span));
+        }
+        let start = self.span();
+        let literal = self
+            .peek()
+            .filter(|l| l.kind == LexemeKind::Number)
+            .map(|l| l.text);
+        if let (Some((Token::UnaryOperator(_, op), minus)), Some(literal)) =
+            (tokens.last(), literal)
+        {
+            // A literal which only fits once negated, such as `-128` for `i8`, is read along with
+            // its sign. As `-` binds looser than `^`, it can't be raised to a power.
+            let minus = *minus;
+            if op.op == UnaryOp::Neg
+                && parse_literal::<T>(literal, false) == Err(LiteralError::OutOfRange)
+            {
+                if let Ok(value) = parse_literal(literal, true) {
+                    self.pos += 1;
+                    let span = minus.to(start);
+                    if self.peek_char() == Some('^') {
+                        return Err(ParserError::LiteralOutOfRange(
+                            start,
+                            literal.into(),
+                            std::any::type_name::<T>(),
+                        ));
+                    }
+                    tokens.pop();
+                    tokens.push((Token::Value(Value::Constant(value)), span));
+                    return Ok(());
+                }
+            }
+        }
         let term = self.parse_term()?;
-        if !negative {
-            return Ok(term);
+        let span = start.to(self.prev_span());
+        if let (Some((Token::UnaryOperator(_, op), minus)), Token::Value(Value::Constant(c))) =
+            (tokens.last(), &term)
+        {
+            // A negative literal which cannot be represented, such as `-5` for an unsigned type,
+            // is rejected here rather than failing every evaluation or saturating to a bound.
+            // Only wrapping negation gives it a value.
+            let modes = self.env.modes();
+            let checked = Modes {
+                integer: IntegerMode::Checked,
+                ..modes
+            };
+            let overflows = || match builtin_unary::<T>('-', checked) {
+                Some(Token::UnaryOperator(_, neg)) => (neg.func)(*c).is_err(),
+                _ => false,
+            };
+            if let Some(literal) = literal.filter(|_| {
+                op.op == UnaryOp::Neg && modes.integer != IntegerMode::Wrapping && overflows()
+            }) {
+                return Err(ParserError::LiteralOutOfRange(
+                    minus.to(span),
+                    format!("-{literal}"),
+                    std::any::type_name::<T>(),
+                ));
+            }
         }
-        if let Token::Value(val) = term {
-            Ok(Token::Value(Value::UnaryOperation(
-                |x| Ok(x.neg()),
-                Box::new(val),
-            )))
-        } else {
-            Err(ParserError::ExpectedToken(self.pos, "term"))
+        tokens.push((term, span));
+        Ok(())
+    }
+
+    fn parse_function(
+        &mut self,
+        function: Function<T>,
+        name_span: Span,
+    ) -> Result<Token<T>, ParserError> {
+        self.assert_char('(')?;
+        let mut args = Vec::new();
+        if !self.check_char(')') {
+            loop {
+                args.push(self.parse_conditional()?);
+                if self.check_char(')') {
+                    break;
+                }
+                self.assert_char(',')?;
+            }
         }
+        let span = name_span.to(self.prev_span());
+        invoke(self.env, function, args, span).map(Token::Value)
     }
 
-    fn parse_function(&mut self, function: Function<T, N>) -> Result<Token<T, N>, ParserError> {
+    fn parse_if(&mut self) -> Result<Token<T>, ParserError> {
         self.assert_char('(')?;
-        let args = if function.args == 0 {
-            self.assert_char(')')?;
-            vec![]
-        } else {
-            let mut args = (0..function.args - 1)
-                .map(|_| self.parse_expression(Some(',')))
-                .collect::<Result<Vec<Value<T, N>>, ParserError>>()?;
-            args.push(self.parse_expression(Some(')'))?);
-            args
-        };
-        Ok(Token::Value(Value::FunctionInvoke(FunctionInvoke::new(
-            function, args,
-        ))))
+        let condition = self.parse_expression(Some(','))?;
+        let if_true = self.parse_expression(Some(','))?;
+        let if_false = self.parse_expression(Some(')'))?;
+        Ok(Token::Value(Value::Conditional(Box::new([
+            condition, if_true, if_false,
+        ]))))
     }
 
-    fn parse_term(&mut self) -> Result<Token<T, N>, ParserError> {
-        match self.peek() {
-            Some('0'..='9') => Ok(Token::Value(Value::Constant(self.parse_number()?))),
+    fn parse_term(&mut self) -> Result<Token<T>, ParserError> {
+        if self.peek().is_some_and(|l| l.kind == LexemeKind::Number) {
+            return Ok(Token::Value(Value::Constant(self.parse_number()?)));
+        }
+        match self.peek_char() {
             Some('(') => {
                 self.pos += 1;
-                self.parse_expression(Some(')')).map(|e| Token::Value(e))
+                self.parse_expression(Some(')')).map(Token::Value)
             }
             _ => {
+                let span = self.span();
                 let name = self.parse_name()?;
-                let value = self
-                    .env
-                    .get(&name)
-                    .ok_or(ParserError::ExpectedToken(self.pos, "name"))?;
-                if let Token::Function(func) = value {
-                    self.parse_function(func.clone())
-                } else {
-                    Ok(value.clone())
+                if name == "if" {
+                    return self.parse_if();
+                }
+                let value = self.env.get(name).ok_or_else(|| {
+                    let suggestion = suggest(name, self.env.names());
+                    ParserError::UnknownName(span, name.to_owned(), suggestion)
+                })?;
+                match value {
+                    Token::Function(func) => self.parse_function(func.clone(), span),
+                    Token::Value(Value::Constant(c)) if !accepts_constant(self.env, *c) => {
+                        Err(ParserError::NonFiniteConstant(span, name.to_owned()))
+                    }
+                    _ => Ok(value.clone()),
                 }
             }
         }
     }
 }
+
+/// Whether a constant may appear in an expression, which under [`FloatMode::Strict`] it only may
+/// if it is finite
+fn accepts_constant<T: Number, const N: usize>(env: &ExprEnv<T, N>, value: T) -> bool {
+    env.modes().float != FloatMode::Strict || value.is_finite()
+}
+
+/// Call a function from the environment, checking the number of arguments
+pub(crate) fn invoke<T: Number, const N: usize>(
+    env: &ExprEnv<T, N>,
+    function: Function<T>,
+    args: Vec<Value<T>>,
+    span: Span,
+) -> Result<Value<T>, ParserError> {
+    if !function.arity.contains(args.len()) {
+        return Err(ParserError::ArgumentCount(
+            span,
+            function.name.to_string(),
+            function.arity,
+            args.len(),
+        ));
+    }
+    let function = if env.modes().float == FloatMode::Strict {
+        function.finite()
+    } else {
+        function
+    };
+    Ok(Value::FunctionInvoke(Box::new(FunctionInvoke::new(
+        function, args, span,
+    ))))
+}
+
+/// Compile a syntax tree against an environment, resolving variables, operators and functions by
+/// name as the parser would. Nodes have no source, so every span is empty.
+pub(crate) fn compile_node<T: Number, const N: usize>(
+    node: Node<T>,
+    env: &ExprEnv<T, N>,
+) -> Result<Value<T>, ParserError> {
+    let span = Span::default();
+    let unknown =
+        |name: &str| ParserError::UnknownName(span, name.to_owned(), suggest(name, env.names()));
+    Ok(match node {
+        Node::Constant(value) if !accepts_constant(env, value) => {
+            return Err(ParserError::NonFiniteConstant(span, format!("{value:?}")));
+        }
+        Node::Constant(value) => Value::Constant(value),
+        Node::Variable(_, name) => match env.get(&name) {
+            Some(Token::Value(value @ Value::Variable(_))) => value.clone(),
+            _ => return Err(unknown(&name)),
+        },
+        Node::Binary(op, args) => {
+            let symbol = op.symbol();
+            let Some(Token::BinaryOperator(_, _, operator)) =
+                builtin_binary(symbol, env.modes()).or_else(|| env.binary_operator(symbol))
+            else {
+                return Err(ParserError::UnknownName(span, symbol.into(), None));
+            };
+            let [left, right] = *args;
+            let args = [compile_node(left, env)?, compile_node(right, env)?];
+            Value::binary(operator, Box::new(args), span)
+        }
+        Node::Unary(op, arg) => {
+            let symbol = op.symbol();
+            let Some(Token::UnaryOperator(_, operator)) = symbol
+                .chars()
+                .next()
+                .and_then(|c| builtin_unary(c, env.modes()))
+                .or_else(|| env.unary_operator(symbol))
+            else {
+                return Err(ParserError::UnknownName(span, symbol.into(), None));
+            };
+            Value::UnaryOperation(operator, Box::new(compile_node(*arg, env)?), span)
+        }
+        Node::Call(name, args) => {
+            let Some(Token::Function(function)) = env.get(&name) else {
+                return Err(unknown(&name));
+            };
+            let args = args
+                .into_iter()
+                .map(|arg| compile_node(arg, env))
+                .collect::<Result<_, _>>()?;
+            invoke(env, function.clone(), args, span)?
+        }
+        Node::Conditional(args) => {
+            let [condition, if_true, if_false] = *args;
+            Value::Conditional(Box::new([
+                compile_node(condition, env)?,
+                compile_node(if_true, env)?,
+                compile_node(if_false, env)?,
+            ]))
+        }
+    })
+}
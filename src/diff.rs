@@ -0,0 +1,319 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    ast::{BinaryOp, UnaryOp},
+    compiler::{builtin_binary, builtin_unary, invoke, ExpressionCompiler, Token},
+    env::ExprEnv,
+    func::FunctionInvoke,
+    parser::{ParserError, Span},
+    Number, Value,
+};
+
+/// An error encountered while differentiating an expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DerivativeError {
+    /// The variable to differentiate with respect to is not in the environment
+    UnknownVariable(String),
+    /// A function whose arguments depend on the variable was not given derivatives with
+    /// [`ExprEnv::with_derivative`], or `^` with an exponent depending on the variable was used
+    /// without an `ln` function of one argument in the environment
+    NoDerivative(String),
+    /// An operator without a derivative, such as a bitwise operator, `/` or `%` for integers, or
+    /// `^` for integers with an exponent depending on the variable
+    NotDifferentiable(&'static str),
+    /// A partial derivative of the named function failed to compile
+    InvalidPartial(String, ParserError),
+}
+
+impl Display for DerivativeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DerivativeError::UnknownVariable(name) => write!(f, "unknown variable `{name}`"),
+            DerivativeError::NoDerivative(name) => write!(f, "function `{name}` has no derivative"),
+            DerivativeError::NotDifferentiable(op) => {
+                write!(f, "operator `{op}` is not differentiable")
+            }
+            DerivativeError::InvalidPartial(name, err) => {
+                write!(f, "invalid derivative of function `{name}`: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DerivativeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DerivativeError::InvalidPartial(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Differentiate a value with respect to the variable at the given index. New operations behave
+/// according to the modes of the environment the value was compiled with.
+pub(crate) fn differentiate<T: Number>(
+    env: &ExprEnv<T, 0>,
+    value: &Value<T>,
+    var: usize,
+) -> Result<Value<T>, DerivativeError> {
+    Ok(Differentiator { env, var }.derive(value)?.flatten_lazy())
+}
+
+struct Differentiator<'a, T: Number> {
+    env: &'a ExprEnv<T, 0>,
+    var: usize,
+}
+
+impl<T: Number> Differentiator<'_, T> {
+    fn depends(&self, value: &Value<T>) -> bool {
+        value.used_variables().contains(&self.var)
+    }
+
+    fn derive(&self, value: &Value<T>) -> Result<Value<T>, DerivativeError> {
+        // Anything not depending on the variable is constant, even if it couldn't be differentiated
+        if !self.depends(value) {
+            return Ok(zero());
+        }
+        Ok(match value {
+            Value::Constant(_) => zero(),
+            Value::Variable(_) => one(),
+            Value::BinaryOperation(op, args, span) | Value::ShortCircuit(op, args, span) => {
+                let [left, right] = &**args;
+                self.derive_binary(op.op, left, right, value, *span)?
+            }
+            Value::UnaryOperation(op, arg, span) => match op.op {
+                UnaryOp::Neg => self.neg(self.derive(arg)?, *span),
+                UnaryOp::Not => zero(),
+                UnaryOp::BitNot => return Err(DerivativeError::NotDifferentiable("~")),
+            },
+            Value::FunctionInvoke(invoke) => self.derive_call(invoke)?,
+            Value::Conditional(args) => {
+                let [condition, if_true, if_false] = &**args;
+                Value::Conditional(Box::new([
+                    condition.clone(),
+                    self.derive(if_true)?,
+                    self.derive(if_false)?,
+                ]))
+            }
+        })
+    }
+
+    fn derive_binary(
+        &self,
+        op: BinaryOp,
+        u: &Value<T>,
+        v: &Value<T>,
+        value: &Value<T>,
+        span: Span,
+    ) -> Result<Value<T>, DerivativeError> {
+        use BinaryOp::*;
+        let du = || self.derive(u);
+        let dv = || self.derive(v);
+        Ok(match op {
+            Or | And | Eq | Ne | Lt | Le | Gt | Ge => zero(),
+            BitOr | BitXor | BitAnd | Shl | Shr => {
+                return Err(DerivativeError::NotDifferentiable(op.symbol()))
+            }
+            // Integer division truncates, so it has no derivative to speak of
+            Div | Rem if !T::FLOAT => return Err(DerivativeError::NotDifferentiable(op.symbol())),
+            Add => self.add(du()?, dv()?, span),
+            Sub => self.sub(du()?, dv()?, span),
+            // u'v + uv'
+            Mul => self.add(
+                self.mul(du()?, v.clone(), span),
+                self.mul(u.clone(), dv()?, span),
+                span,
+            ),
+            // (u'v - uv') / v^2
+            Div => self.div(
+                self.sub(
+                    self.mul(du()?, v.clone(), span),
+                    self.mul(u.clone(), dv()?, span),
+                    span,
+                ),
+                self.mul(v.clone(), v.clone(), span),
+                span,
+            ),
+            // u % v is u - v * trunc(u / v), where the quotient is u - u % v divided by v
+            Rem => {
+                let quotient = self.div(
+                    self.sub(
+                        u.clone(),
+                        self.binary(Rem, u.clone(), v.clone(), span),
+                        span,
+                    ),
+                    v.clone(),
+                    span,
+                );
+                self.sub(du()?, self.mul(dv()?, quotient, span), span)
+            }
+            // v u^(v - 1) u', or 0 where v is 0, as u^-1 would fail for integers or be infinite
+            // for floats at u = 0
+            Pow if !self.depends(v) => {
+                let exponent = self.sub(v.clone(), one(), span);
+                let derivative = self.mul(
+                    self.mul(v.clone(), self.binary(Pow, u.clone(), exponent, span), span),
+                    du()?,
+                    span,
+                );
+                if matches!(v, Value::Constant(_)) || is_zero(&derivative) {
+                    derivative
+                } else {
+                    Value::Conditional(Box::new([
+                        self.binary(Eq, v.clone(), zero(), span),
+                        zero(),
+                        derivative,
+                    ]))
+                }
+            }
+            Pow if !T::FLOAT => return Err(DerivativeError::NotDifferentiable("^")),
+            // u^v ln(u) v'
+            Pow if !self.depends(u) => self.mul(
+                self.mul(value.clone(), self.ln(u.clone(), span)?, span),
+                dv()?,
+                span,
+            ),
+            // u^v (v' ln(u) + v u' / u)
+            Pow => self.mul(
+                value.clone(),
+                self.add(
+                    self.mul(dv()?, self.ln(u.clone(), span)?, span),
+                    self.div(self.mul(v.clone(), du()?, span), u.clone(), span),
+                    span,
+                ),
+                span,
+            ),
+        })
+    }
+
+    /// The chain rule: the sum of each partial derivative times the derivative of its argument
+    fn derive_call(&self, invoke: &FunctionInvoke<T>) -> Result<Value<T>, DerivativeError> {
+        let name = &invoke.func.name;
+        let Some(partials) = &invoke.func.partials else {
+            return Err(DerivativeError::NoDerivative(name.to_string()));
+        };
+        let scope = self.env.partials_scope(partials);
+        let mut sum = zero();
+        for (source, arg) in partials.sources.iter().zip(&invoke.args) {
+            let arg_derivative = self.derive(arg)?;
+            if is_zero(&arg_derivative) {
+                continue;
+            }
+            let partial = ExpressionCompiler::compile(source.as_str(), &scope)
+                .map_err(|err| DerivativeError::InvalidPartial(name.to_string(), err))?;
+            let partial = substitute(partial, &invoke.args, invoke.span);
+            sum = self.add(
+                sum,
+                self.mul(partial, arg_derivative, invoke.span),
+                invoke.span,
+            );
+        }
+        Ok(sum)
+    }
+
+    fn binary(&self, op: BinaryOp, left: Value<T>, right: Value<T>, span: Span) -> Value<T> {
+        let Some(Token::BinaryOperator(_, _, operator)) =
+            builtin_binary(op.symbol(), self.env.modes())
+        else {
+            unreachable!("not a built-in operator")
+        };
+        Value::binary(operator, Box::new([left, right]), span)
+    }
+
+    fn add(&self, left: Value<T>, right: Value<T>, span: Span) -> Value<T> {
+        if is_zero(&left) {
+            right
+        } else if is_zero(&right) {
+            left
+        } else {
+            self.binary(BinaryOp::Add, left, right, span)
+        }
+    }
+
+    fn sub(&self, left: Value<T>, right: Value<T>, span: Span) -> Value<T> {
+        if is_zero(&right) {
+            left
+        } else if is_zero(&left) {
+            self.neg(right, span)
+        } else {
+            self.binary(BinaryOp::Sub, left, right, span)
+        }
+    }
+
+    fn mul(&self, left: Value<T>, right: Value<T>, span: Span) -> Value<T> {
+        if is_zero(&left) || is_zero(&right) {
+            zero()
+        } else if is_one(&left) {
+            right
+        } else if is_one(&right) {
+            left
+        } else {
+            self.binary(BinaryOp::Mul, left, right, span)
+        }
+    }
+
+    fn div(&self, left: Value<T>, right: Value<T>, span: Span) -> Value<T> {
+        if is_zero(&left) {
+            zero()
+        } else if is_one(&right) {
+            left
+        } else {
+            self.binary(BinaryOp::Div, left, right, span)
+        }
+    }
+
+    fn neg(&self, arg: Value<T>, span: Span) -> Value<T> {
+        if is_zero(&arg) {
+            return arg;
+        }
+        let Some(Token::UnaryOperator(_, operator)) = builtin_unary('-', self.env.modes()) else {
+            unreachable!("not a built-in operator")
+        };
+        Value::UnaryOperation(operator, Box::new(arg), span)
+    }
+
+    /// A call to the environment's `ln`, which is only reached for floats
+    fn ln(&self, arg: Value<T>, span: Span) -> Result<Value<T>, DerivativeError> {
+        match self.env.get("ln") {
+            Some(Token::Function(ln)) => invoke(self.env, ln.clone(), vec![arg], span)
+                .map_err(|_| DerivativeError::NoDerivative("ln".into())),
+            _ => Err(DerivativeError::NoDerivative("ln".into())),
+        }
+    }
+}
+
+fn zero<T: Number>() -> Value<T> {
+    Value::Constant(T::default())
+}
+
+fn one<T: Number>() -> Value<T> {
+    Value::Constant(T::from_bool(true))
+}
+
+pub(crate) fn is_zero<T: Number>(value: &Value<T>) -> bool {
+    matches!(value, Value::Constant(c) if *c == T::default())
+}
+
+pub(crate) fn is_one<T: Number>(value: &Value<T>) -> bool {
+    matches!(value, Value::Constant(c) if *c == T::from_bool(true))
+}
+
+/// Replace the variables of a compiled partial derivative with the arguments they stand for,
+/// attributing its operations to the call being differentiated
+fn substitute<T: Number>(value: Value<T>, args: &[Value<T>], span: Span) -> Value<T> {
+    let sub = |value| substitute(value, args, span);
+    match value {
+        Value::Constant(_) => value,
+        Value::Variable(index) => args[index].clone(),
+        Value::BinaryOperation(op, operands, _) | Value::ShortCircuit(op, operands, _) => {
+            Value::binary(op, Box::new(operands.map(sub)), span)
+        }
+        Value::UnaryOperation(op, arg, _) => Value::UnaryOperation(op, Box::new(sub(*arg)), span),
+        Value::FunctionInvoke(invoke) => Value::FunctionInvoke(Box::new(FunctionInvoke::new(
+            invoke.func,
+            invoke.args.into_iter().map(sub).collect(),
+            span,
+        ))),
+        Value::Conditional(operands) => Value::Conditional(Box::new(operands.map(sub))),
+    }
+}
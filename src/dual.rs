@@ -0,0 +1,417 @@
+use std::{array, cmp::Ordering, str::FromStr};
+
+use crate::{
+    compiler::{builtin_binary, builtin_unary, ExpressionCompiler, Token},
+    diff::DerivativeError,
+    env::ExprEnv,
+    func::{Function, FunctionInvoke},
+    number::{
+        Add, Boolean, Div, FloatOps, LiteralError, Logarithm, Mul, OverflowOps, ParseRadix, Pow,
+        Rem, Sub, Trig,
+    },
+    EvalError, Number, Value,
+};
+
+/// A dual number, carrying a value along with its gradient with respect to N variables, for
+/// forward-mode automatic differentiation. Evaluating an expression with dual numbers computes
+/// its value and gradient together.
+///
+/// Comparisons only consider the value. The component type must be a float, as the derivative of
+/// `^` needs a logarithm.
+///
+/// Example:
+/// ```
+/// use crunch_eval::{dual::Dual, env::ExprEnv, expr::Expr};
+///
+/// let env = ExprEnv::new(["x"]).with_trig();
+/// let expr = Expr::compile_env("x * sin(x)", env).unwrap();
+/// let result: Dual<f64> = expr.evaluate(&[Dual::variable(0.0, 0)]).unwrap();
+/// assert_eq!((result.value, result.gradient), (0.0, [0.0]));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Dual<T, const N: usize = 1> {
+    pub value: T,
+    /// The partial derivative of the value with respect to each variable
+    pub gradient: [T; N],
+}
+
+impl<T: Number, const N: usize> Dual<T, N> {
+    /// A value which doesn't depend on any variable
+    pub fn constant(value: T) -> Self {
+        Dual {
+            value,
+            gradient: [T::default(); N],
+        }
+    }
+
+    /// The variable at the given index, whose derivative is 1 with respect to itself
+    pub fn variable(value: T, index: usize) -> Self {
+        let mut dual = Self::constant(value);
+        dual.gradient[index] = T::from_bool(true);
+        dual
+    }
+}
+
+// The components are floats, so their wrapping operations are the ordinary ones and never fail
+impl<T: Number + Logarithm, const N: usize> Dual<T, N> {
+    fn plus(self, other: Self) -> Self {
+        Dual {
+            value: self.value.wrapping_add(other.value),
+            gradient: array::from_fn(|i| self.gradient[i].wrapping_add(other.gradient[i])),
+        }
+    }
+
+    fn minus(self, other: Self) -> Self {
+        Dual {
+            value: self.value.wrapping_sub(other.value),
+            gradient: array::from_fn(|i| self.gradient[i].wrapping_sub(other.gradient[i])),
+        }
+    }
+
+    fn times(self, other: Self) -> Self {
+        Dual {
+            value: self.value.wrapping_mul(other.value),
+            gradient: array::from_fn(|i| {
+                let by_self = self.gradient[i].wrapping_mul(other.value);
+                by_self.wrapping_add(self.value.wrapping_mul(other.gradient[i]))
+            }),
+        }
+    }
+
+    fn negated(self) -> Self {
+        Dual {
+            value: self.value.wrapping_neg(),
+            gradient: self.gradient.map(|g| g.wrapping_neg()),
+        }
+    }
+
+    /// Divide using `div` for the components, which may refuse a zero divisor
+    fn quotient(self, other: Self, div: impl Fn(&T, T) -> Option<T>) -> Option<Self> {
+        let value = div(&self.value, other.value)?;
+        let mut gradient = [T::default(); N];
+        for (i, g) in gradient.iter_mut().enumerate() {
+            let numerator = self.gradient[i].wrapping_sub(value.wrapping_mul(other.gradient[i]));
+            *g = div(&numerator, other.value)?;
+        }
+        Some(Dual { value, gradient })
+    }
+
+    /// The remainder using `rem` and `div` for the components. It is `self - other * q` for the
+    /// truncated quotient `q`, which is constant almost everywhere.
+    fn remainder(
+        self,
+        other: Self,
+        rem: impl Fn(&T, T) -> Option<T>,
+        div: impl Fn(&T, T) -> Option<T>,
+    ) -> Option<Self> {
+        let value = rem(&self.value, other.value)?;
+        let quotient = div(&self.value.wrapping_sub(value), other.value)?;
+        Some(Dual {
+            value,
+            gradient: array::from_fn(|i| {
+                self.gradient[i].wrapping_sub(quotient.wrapping_mul(other.gradient[i]))
+            }),
+        })
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> Add for Dual<T, N> {
+    fn add(&self, other: Self) -> Option<Self> {
+        Some(self.plus(other))
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> Sub for Dual<T, N> {
+    fn sub(&self, other: Self) -> Option<Self> {
+        Some(self.minus(other))
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> Mul for Dual<T, N> {
+    fn mul(&self, other: Self) -> Option<Self> {
+        Some(self.times(other))
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> Div for Dual<T, N> {
+    fn div(&self, other: Self) -> Option<Self> {
+        self.quotient(other, Div::div)
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> Rem for Dual<T, N> {
+    fn rem(&self, other: Self) -> Option<Self> {
+        self.remainder(other, Rem::rem, Div::div)
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> Pow<Self> for Dual<T, N> {
+    fn pow(&self, exp: Self) -> Result<Self, EvalError> {
+        let value = self.value.pow(exp.value)?;
+        // d(a^b) = b a^(b - 1) da + a^b ln(a) db, skipping terms whose derivative is zero so that
+        // they can't add NaN, as a negative base would to the logarithm. The first term is 0 for
+        // b = 0 even where a^(b - 1) is infinite.
+        let one = T::from_bool(true);
+        let zero = T::default();
+        let by_base = if exp.value == zero {
+            zero
+        } else {
+            exp.value
+                .wrapping_mul(self.value.pow(exp.value.wrapping_sub(one))?)
+        };
+        let by_exp = value.wrapping_mul(self.value.ln());
+        let gradient = array::from_fn(|i| {
+            let mut g = zero;
+            if self.gradient[i] != zero {
+                g = g.wrapping_add(by_base.wrapping_mul(self.gradient[i]));
+            }
+            if exp.gradient[i] != zero {
+                g = g.wrapping_add(by_exp.wrapping_mul(exp.gradient[i]));
+            }
+            g
+        });
+        Ok(Dual { value, gradient })
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> OverflowOps for Dual<T, N> {
+    fn wrapping_add(&self, other: Self) -> Self {
+        self.plus(other)
+    }
+
+    fn wrapping_sub(&self, other: Self) -> Self {
+        self.minus(other)
+    }
+
+    fn wrapping_mul(&self, other: Self) -> Self {
+        self.times(other)
+    }
+
+    fn wrapping_div(&self, other: Self) -> Option<Self> {
+        Div::div(self, other)
+    }
+
+    fn wrapping_rem(&self, other: Self) -> Option<Self> {
+        Rem::rem(self, other)
+    }
+
+    fn wrapping_neg(&self) -> Self {
+        self.negated()
+    }
+
+    fn wrapping_pow(&self, exp: Self) -> Result<Self, EvalError> {
+        Pow::pow(self, exp)
+    }
+
+    fn saturating_add(&self, other: Self) -> Self {
+        self.plus(other)
+    }
+
+    fn saturating_sub(&self, other: Self) -> Self {
+        self.minus(other)
+    }
+
+    fn saturating_mul(&self, other: Self) -> Self {
+        self.times(other)
+    }
+
+    fn saturating_div(&self, other: Self) -> Option<Self> {
+        Div::div(self, other)
+    }
+
+    fn saturating_rem(&self, other: Self) -> Option<Self> {
+        Rem::rem(self, other)
+    }
+
+    fn saturating_neg(&self) -> Self {
+        self.negated()
+    }
+
+    fn saturating_pow(&self, exp: Self) -> Result<Self, EvalError> {
+        Pow::pow(self, exp)
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> FloatOps for Dual<T, N> {
+    const FLOAT: bool = T::FLOAT;
+
+    fn is_finite(&self) -> bool {
+        self.value.is_finite() && self.gradient.iter().all(FloatOps::is_finite)
+    }
+
+    fn ieee_div(&self, other: Self) -> Option<Self> {
+        self.quotient(other, FloatOps::ieee_div)
+    }
+
+    fn ieee_rem(&self, other: Self) -> Option<Self> {
+        self.remainder(other, FloatOps::ieee_rem, FloatOps::ieee_div)
+    }
+}
+
+impl<T: Number, const N: usize> Boolean for Dual<T, N> {
+    fn from_bool(b: bool) -> Self {
+        Self::constant(T::from_bool(b))
+    }
+
+    fn to_bool(&self) -> bool {
+        self.value.to_bool()
+    }
+}
+
+impl<T: Number, const N: usize> Default for Dual<T, N> {
+    fn default() -> Self {
+        Self::constant(T::default())
+    }
+}
+
+impl<T: Number, const N: usize> PartialEq for Dual<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<T: Number, const N: usize> PartialOrd for Dual<T, N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.value.partial_cmp(&other.value)
+    }
+}
+
+impl<T: Number, const N: usize> FromStr for Dual<T, N> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self::constant)
+    }
+}
+
+impl<T: Number, const N: usize> ParseRadix for Dual<T, N> {
+    fn parse_radix(digits: &str, radix: u32) -> Result<Self, LiteralError> {
+        T::parse_radix(digits, radix).map(Self::constant)
+    }
+}
+
+impl<T: Number + Logarithm + Trig, const N: usize> Trig for Dual<T, N> {
+    fn sin(&self) -> Self {
+        let cos = self.value.cos();
+        Dual {
+            value: self.value.sin(),
+            gradient: self.gradient.map(|g| g.wrapping_mul(cos)),
+        }
+    }
+
+    fn cos(&self) -> Self {
+        let minus_sin = self.value.sin().wrapping_neg();
+        Dual {
+            value: self.value.cos(),
+            gradient: self.gradient.map(|g| g.wrapping_mul(minus_sin)),
+        }
+    }
+
+    fn tan(&self) -> Self {
+        let tan = self.value.tan();
+        let sec_squared = T::from_bool(true).wrapping_add(tan.wrapping_mul(tan));
+        Dual {
+            value: tan,
+            gradient: self.gradient.map(|g| g.wrapping_mul(sec_squared)),
+        }
+    }
+}
+
+/// Re-instantiate a compiled value with dual numbers, under the modes of the environment it was
+/// compiled with. Functions are given their partial derivatives, unless their arguments are
+/// constant.
+pub(crate) fn to_dual<T: Number + Logarithm, const N: usize>(
+    value: &Value<T>,
+    env: &ExprEnv<T, 0>,
+) -> Result<Value<Dual<T, N>>, DerivativeError> {
+    let convert = |value: &Value<T>| to_dual::<T, N>(value, env);
+    let modes = env.modes();
+    Ok(match value {
+        Value::Constant(c) => Value::Constant(Dual::constant(*c)),
+        Value::Variable(index) => Value::Variable(*index),
+        Value::BinaryOperation(op, args, span) | Value::ShortCircuit(op, args, span) => {
+            let symbol = op.op.symbol();
+            let Some(Token::BinaryOperator(_, _, operator)) = builtin_binary(symbol, modes) else {
+                return Err(DerivativeError::NotDifferentiable(symbol));
+            };
+            let [left, right] = &**args;
+            Value::binary(operator, Box::new([convert(left)?, convert(right)?]), *span)
+        }
+        Value::UnaryOperation(op, arg, span) => {
+            let symbol = op.op.symbol();
+            let Some(Token::UnaryOperator(_, operator)) =
+                symbol.chars().next().and_then(|c| builtin_unary(c, modes))
+            else {
+                return Err(DerivativeError::NotDifferentiable(symbol));
+            };
+            Value::UnaryOperation(operator, Box::new(convert(arg)?), *span)
+        }
+        Value::FunctionInvoke(invoke) => {
+            let args = invoke
+                .args
+                .iter()
+                .map(convert)
+                .collect::<Result<Vec<_>, _>>()?;
+            Value::FunctionInvoke(Box::new(FunctionInvoke::new(
+                dual_function(invoke, env)?,
+                args,
+                invoke.span,
+            )))
+        }
+        Value::Conditional(args) => {
+            let [condition, if_true, if_false] = &**args;
+            Value::Conditional(Box::new([
+                convert(condition)?,
+                convert(if_true)?,
+                convert(if_false)?,
+            ]))
+        }
+    })
+}
+
+/// Wrap a function to compute its gradient by the chain rule, from its partial derivatives
+fn dual_function<T: Number + Logarithm, const N: usize>(
+    invoke: &FunctionInvoke<T>,
+    env: &ExprEnv<T, 0>,
+) -> Result<Function<Dual<T, N>>, DerivativeError> {
+    let func = invoke.func.clone();
+    let partials = match &func.partials {
+        Some(partials) => {
+            let scope = env.partials_scope(partials);
+            partials
+                .sources
+                .iter()
+                .map(|source| {
+                    ExpressionCompiler::compile(source.as_str(), &scope)
+                        .map_err(|err| DerivativeError::InvalidPartial(func.name.to_string(), err))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        None if invoke
+            .args
+            .iter()
+            .all(|arg| arg.used_variables().is_empty()) =>
+        {
+            Vec::new()
+        }
+        None => return Err(DerivativeError::NoDerivative(func.name.to_string())),
+    };
+    Ok(Function::new_checked_variadic(
+        &func.name.clone(),
+        func.arity,
+        move |args: &[Dual<T, N>]| {
+            let values: Vec<T> = args.iter().map(|arg| arg.value).collect();
+            let mut result = Dual::constant(func.call(&values)?);
+            for (partial, arg) in partials.iter().zip(args) {
+                if arg.gradient.iter().all(|&g| g == T::default()) {
+                    continue;
+                }
+                let partial = partial.evaluate(&values)?;
+                for (g, dx) in result.gradient.iter_mut().zip(arg.gradient) {
+                    *g = g.wrapping_add(partial.wrapping_mul(dx));
+                }
+            }
+            Ok(result)
+        },
+    ))
+}
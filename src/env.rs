@@ -1,10 +1,14 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::RangeBounds, rc::Rc};
 
 use crate::{
-    compiler::Token,
-    func::{CustomFunc, Function},
-    number::Trig,
-    Number, Value,
+    ast::{BinaryOp, UnaryOp},
+    compiler::{binary, unary, Modes, Token},
+    func::{Arity, CustomError, CustomFunc, FallibleFunc, Function, Partials},
+    number::{
+        Bitwise, FloatMode, Hyperbolic, IntegerMode, InverseTrig, Logarithm, MathConsts, Roots,
+        Rounding, Sign, StdMath, Trig,
+    },
+    EvalError, Number, Value,
 };
 
 /// An environment specifying variables and functions an expression can use
@@ -20,15 +24,20 @@ use crate::{
 /// assert_eq!(val, 28);
 /// ```
 pub struct ExprEnv<T: Number, const N: usize> {
-    named_tokens: HashMap<String, Token<T, N>>,
+    named_tokens: HashMap<String, Token<T>>,
+    /// Variable names in the order their values are passed
+    var_names: Vec<String>,
+    /// Operators beyond the built-in ones, keyed by symbol
+    binary_operators: HashMap<&'static str, Token<T>>,
+    unary_operators: HashMap<&'static str, Token<T>>,
+    integer_mode: IntegerMode,
+    float_mode: FloatMode,
 }
 
 impl<T: Number> Default for ExprEnv<T, 0> {
     /// An ExprEnv with no variables and no functions
     fn default() -> Self {
-        ExprEnv {
-            named_tokens: HashMap::new(),
-        }
+        ExprEnv::new([])
     }
 }
 
@@ -42,13 +51,143 @@ impl<T: Number, const N: usize> ExprEnv<T, N> {
                 .enumerate()
                 .map(|(index, elem)| (elem.clone(), Token::Value(Value::Variable(index))))
                 .collect(),
+            var_names: var_names.map(|s| s.to_owned()).to_vec(),
+            binary_operators: HashMap::new(),
+            unary_operators: HashMap::new(),
+            integer_mode: IntegerMode::default(),
+            float_mode: FloatMode::default(),
         }
     }
 
-    pub(crate) fn get(&self, name: &str) -> Option<&Token<T, N>> {
+    pub(crate) fn var_names(&self) -> &[String] {
+        &self.var_names
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Token<T>> {
         self.named_tokens.get(name)
     }
 
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.named_tokens.keys().map(String::as_str)
+    }
+
+    /// The integer and float modes, where the float mode is always [`FloatMode::Standard`] for
+    /// integer types
+    pub(crate) fn modes(&self) -> Modes {
+        Modes {
+            integer: self.integer_mode,
+            float: if T::FLOAT {
+                self.float_mode
+            } else {
+                FloatMode::Standard
+            },
+        }
+    }
+
+    /// Drop the variable count from the type, keeping everything else
+    pub(crate) fn erase(self) -> ExprEnv<T, 0> {
+        ExprEnv {
+            named_tokens: self.named_tokens,
+            var_names: self.var_names,
+            binary_operators: self.binary_operators,
+            unary_operators: self.unary_operators,
+            integer_mode: self.integer_mode,
+            float_mode: self.float_mode,
+        }
+    }
+
+    /// An environment for compiling a function's partial derivatives, whose variables are the
+    /// function's parameters and whose names are as seen from within the function's namespace
+    pub(crate) fn partials_scope(&self, partials: &Partials) -> ExprEnv<T, 0> {
+        let mut named_tokens: HashMap<String, Token<T>> = self
+            .named_tokens
+            .iter()
+            .filter(|(_, token)| !matches!(token, Token::Value(Value::Variable(_))))
+            .map(|(name, token)| (name.clone(), token.clone()))
+            .collect();
+        if !partials.namespace.is_empty() {
+            let prefix = format!("{}.", partials.namespace);
+            let members: Vec<_> = named_tokens
+                .iter()
+                .filter_map(|(name, token)| Some((name.strip_prefix(&prefix)?, token)))
+                .map(|(name, token)| (name.to_owned(), token.clone()))
+                .collect();
+            named_tokens.extend(members);
+        }
+        named_tokens.extend(
+            partials
+                .params
+                .iter()
+                .enumerate()
+                .map(|(index, name)| (name.clone(), Token::Value(Value::Variable(index)))),
+        );
+        ExprEnv {
+            named_tokens,
+            var_names: partials.params.clone(),
+            binary_operators: self.binary_operators.clone(),
+            unary_operators: self.unary_operators.clone(),
+            integer_mode: self.integer_mode,
+            float_mode: self.float_mode,
+        }
+    }
+
+    pub(crate) fn binary_operator(&self, symbol: &str) -> Option<Token<T>> {
+        self.binary_operators.get(symbol).cloned()
+    }
+
+    pub(crate) fn unary_operator(&self, symbol: &str) -> Option<Token<T>> {
+        self.unary_operators.get(symbol).cloned()
+    }
+
+    fn with_operator(mut self, token: Token<T>) -> Self {
+        match &token {
+            Token::BinaryOperator(_, _, op) => self.binary_operators.insert(op.op.symbol(), token),
+            Token::UnaryOperator(_, op) => self.unary_operators.insert(op.op.symbol(), token),
+            _ => unreachable!("not an operator"),
+        };
+        self
+    }
+
+    /// Set how the built-in arithmetic operators handle integer overflow. By default they return
+    /// [`EvalError::Overflow`](crate::EvalError::Overflow). Functions such as `abs` are not
+    /// affected, and neither are floats.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv, number::IntegerMode};
+    ///
+    /// let env = ExprEnv::new(["x"]).with_integer_mode(IntegerMode::Wrapping);
+    /// let expr = Expr::compile_env("x * 2", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[200u8]).unwrap(), 144);
+    ///
+    /// let env = ExprEnv::new(["x"]).with_integer_mode(IntegerMode::Saturating);
+    /// let expr = Expr::compile_env("x * 2", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[200u8]).unwrap(), 255);
+    /// ```
+    pub fn with_integer_mode(mut self, mode: IntegerMode) -> Self {
+        self.integer_mode = mode;
+        self
+    }
+
+    /// Set how the arithmetic operators and functions handle floating-point results which are
+    /// not finite. This has no effect on integer types.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv, number::FloatMode, EvalError};
+    ///
+    /// let env = ExprEnv::new(["x"]).with_float_mode(FloatMode::Strict);
+    /// let expr = Expr::compile_env("x - x * 2", env).unwrap();
+    /// let err = expr.evaluate(&[f64::INFINITY]).unwrap_err();
+    /// assert!(matches!(err.root(), EvalError::NonFinite));
+    ///
+    /// let env = ExprEnv::new(["x"]).with_float_mode(FloatMode::Ieee);
+    /// let expr = Expr::compile_env("1 / x", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[0.0]).unwrap(), f64::INFINITY);
+    /// ```
+    pub fn with_float_mode(mut self, mode: FloatMode) -> Self {
+        self.float_mode = mode;
+        self
+    }
+
     /// Add a custom function that can be used during evaluation
     /// Example:
     /// ```
@@ -64,18 +203,423 @@ impl<T: Number, const N: usize> ExprEnv<T, N> {
         name: impl Into<String>,
         func: F,
     ) -> Self {
-        let function = Function::new::<A, F>(func);
+        let name = name.into();
+        let function = Function::new::<A, F>(&name, func);
+        self.named_tokens.insert(name, Token::Function(function));
+        self
+    }
+
+    /// Add a custom function which can fail. Its errors are returned from evaluation as
+    /// [`EvalError::Custom`](crate::EvalError::Custom).
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv, EvalError};
+    ///
+    /// let env = ExprEnv::default().with_fallible_func("sqrt", |[x]: [f64; 1]| {
+    ///     if x < 0.0 {
+    ///         Err("square root of a negative number")
+    ///     } else {
+    ///         Ok(x.sqrt())
+    ///     }
+    /// });
+    /// let expr = Expr::compile_env("sqrt(0 - 4)", env).unwrap();
+    /// let err = expr.evaluate_blank().unwrap_err();
+    /// assert!(matches!(err.root(), EvalError::Custom(_)));
+    /// ```
+    pub fn with_fallible_func<E: Into<CustomError>, const A: usize, F: FallibleFunc<T, E, N, A>>(
+        mut self,
+        name: impl Into<String>,
+        func: F,
+    ) -> Self {
+        let name = name.into();
+        let function = Function::new_fallible::<E, A, F>(&name, func);
+        self.named_tokens.insert(name, Token::Function(function));
+        self
+    }
+
+    /// Add a custom function accepting a variable number of arguments, within the given range.
+    /// Panics if the range is empty.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv};
+    ///
+    /// let env = ExprEnv::default()
+    ///     .with_variadic_func("sum", 1.., |args: &[i32]| args.iter().sum());
+    /// let expr = Expr::compile_env("sum(1, 2, 3)", env).unwrap();
+    /// assert_eq!(expr.evaluate_blank().unwrap(), 6);
+    /// ```
+    pub fn with_variadic_func<F: 'static + Fn(&[T]) -> T>(
+        mut self,
+        name: impl Into<String>,
+        arity: impl RangeBounds<usize>,
+        func: F,
+    ) -> Self {
+        let name = name.into();
+        let function = Function::new_variadic(&name, Arity::from_range(arity), func);
+        self.named_tokens.insert(name, Token::Function(function));
+        self
+    }
+
+    /// Give a function added earlier its partial derivatives, so that expressions calling it can
+    /// be differentiated with [`Expr::derivative`](crate::expr::Expr::derivative). Each partial
+    /// derivative is written in terms of the parameter names, and can use any function, constant
+    /// or operator in the environment it is differentiated in.
+    ///
+    /// # Panics
+    /// If there is no function with this name taking exactly `A` arguments.
+    ///
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv};
+    ///
+    /// let env = ExprEnv::new(["x"])
+    ///     .with_func("cube", |[x]: [f64; 1]| x * x * x)
+    ///     .with_derivative("cube", ["x"], ["3 * x ^ 2"]);
+    /// let expr = Expr::compile_env("cube(2 * x)", env).unwrap();
+    /// let derivative = expr.derivative("x").unwrap();
+    /// assert_eq!(derivative.evaluate(&[1.0]).unwrap(), 24.0);
+    /// ```
+    pub fn with_derivative<const A: usize>(
+        mut self,
+        name: &str,
+        params: [&str; A],
+        partials: [&str; A],
+    ) -> Self {
+        match self.named_tokens.get_mut(name) {
+            Some(Token::Function(func)) if func.arity == Arity::exactly(A) => {
+                func.partials = Some(Rc::new(Partials {
+                    params: params.map(str::to_owned).to_vec(),
+                    sources: partials.map(str::to_owned).to_vec(),
+                    namespace: String::new(),
+                }));
+            }
+            _ => panic!(
+                "no function `{name}` taking {} to differentiate",
+                Arity::exactly(A)
+            ),
+        }
+        self
+    }
+
+    /// Add a named constant, which is inlined into expressions using it
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv};
+    ///
+    /// let env = ExprEnv::new(["x"]).with_const("g", 9.81);
+    /// let expr = Expr::compile_env("g * x", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[2.0]).unwrap(), 19.62);
+    /// ```
+    pub fn with_const(mut self, name: impl Into<String>, value: T) -> Self {
+        self.named_tokens
+            .insert(name.into(), Token::Value(Value::Constant(value)));
+        self
+    }
+
+    /// Add functions and constants under a namespace, so that they are named `namespace.name`.
+    /// The closure is given an empty environment with the same modes to register them in. Only
+    /// names are scoped, so modes and operators apply to the whole environment and must be set
+    /// outside the closure.
+    ///
+    /// # Panics
+    /// If the closure changes the integer or float mode, or adds operators, as with
+    /// [`with_bitwise`](Self::with_bitwise).
+    ///
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv};
+    ///
+    /// let env = ExprEnv::new(["player.health"]).with_namespace("stats", |stats| {
+    ///     stats.with_variadic_func("mean", 1.., |args: &[f64]| {
+    ///         args.iter().sum::<f64>() / args.len() as f64
+    ///     })
+    /// });
+    /// let expr = Expr::compile_env("stats.mean(player.health, 50)", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[100.0]).unwrap(), 75.0);
+    /// ```
+    pub fn with_namespace(mut self, namespace: &str, build: impl FnOnce(Self) -> Self) -> Self {
+        let env = build(ExprEnv {
+            named_tokens: HashMap::new(),
+            var_names: Vec::new(),
+            binary_operators: HashMap::new(),
+            unary_operators: HashMap::new(),
+            integer_mode: self.integer_mode,
+            float_mode: self.float_mode,
+        });
+        assert!(
+            env.binary_operators.is_empty() && env.unary_operators.is_empty(),
+            "operators can't be namespaced, so add them outside `with_namespace`"
+        );
+        assert!(
+            env.modes() == self.modes(),
+            "modes apply to the whole environment, so set them outside `with_namespace`"
+        );
+        for (name, mut token) in env.named_tokens {
+            let name = format!("{namespace}.{name}");
+            if let Token::Function(func) = &mut token {
+                func.name = name.as_str().into();
+                if let Some(partials) = &mut func.partials {
+                    let partials = Rc::make_mut(partials);
+                    partials.namespace = if partials.namespace.is_empty() {
+                        namespace.to_owned()
+                    } else {
+                        format!("{namespace}.{}", partials.namespace)
+                    };
+                }
+            }
+            self.named_tokens.insert(name, token);
+        }
+        self
+    }
+
+    /// Add a built-in function which reports overflow by returning `None`
+    fn with_checked_func<const A: usize, F: 'static + Fn([T; A]) -> Option<T>>(
+        mut self,
+        name: &str,
+        func: F,
+    ) -> Self {
+        let function =
+            Function::new_checked::<A, _>(name, move |args| func(args).ok_or(EvalError::Overflow));
         self.named_tokens
-            .insert(name.into(), Token::Function(function));
+            .insert(name.to_owned(), Token::Function(function));
         self
     }
+
+    /// Add functions comparing their arguments (min, max, clamp). Only clamp has derivatives, as
+    /// those of a function must be given for a fixed number of arguments.
+    pub fn with_min_max(self) -> Self {
+        self.with_variadic_func("min", 1.., |args: &[T]| {
+            args[1..]
+                .iter()
+                .fold(args[0], |min, &x| if x < min { x } else { min })
+        })
+        .with_variadic_func("max", 1.., |args: &[T]| {
+            args[1..]
+                .iter()
+                .fold(args[0], |max, &x| if x > max { x } else { max })
+        })
+        .with_func("clamp", |[x, low, high]: [T; 3]| {
+            if x < low {
+                low
+            } else if x > high {
+                high
+            } else {
+                x
+            }
+        })
+        .with_derivative(
+            "clamp",
+            ["x", "low", "high"],
+            ["x >= low && x <= high", "x < low", "x > high"],
+        )
+    }
+}
+
+impl<T: Number + Bitwise, const N: usize> ExprEnv<T, N> {
+    /// Add bitwise operators for integer types: `|`, `xor`, `&`, `<<` and `>>` in increasing
+    /// order of precedence, all binding looser than arithmetic and tighter than comparisons,
+    /// and the prefix `~`. Shifting by a negative amount or by at least the bit width of the
+    /// type is an overflow.
+    ///
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv};
+    ///
+    /// let env = ExprEnv::new(["flags"]).with_bitwise();
+    /// let expr = Expr::compile_env("flags & 1 << 3 != 0", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[0b1010u32]).unwrap(), 1);
+    /// ```
+    pub fn with_bitwise(self) -> Self {
+        self.with_operator(binary(BinaryOp::BitOr, |a, b| Ok(a.bit_or(b))))
+            .with_operator(binary(BinaryOp::BitXor, |a, b| Ok(a.bit_xor(b))))
+            .with_operator(binary(BinaryOp::BitAnd, |a, b| Ok(a.bit_and(b))))
+            .with_operator(binary(BinaryOp::Shl, |a, b| {
+                a.shl(b).ok_or(EvalError::Overflow)
+            }))
+            .with_operator(binary(BinaryOp::Shr, |a, b| {
+                a.shr(b).ok_or(EvalError::Overflow)
+            }))
+            .with_operator(unary(UnaryOp::BitNot, |x| Ok(x.bit_not())))
+    }
+}
+
+impl<T: Number + MathConsts, const N: usize> ExprEnv<T, N> {
+    /// Add math constants (pi, e, tau, phi, inf, nan)
+    pub fn with_math_constants(self) -> Self {
+        self.with_const("pi", T::PI)
+            .with_const("e", T::E)
+            .with_const("tau", T::TAU)
+            .with_const("phi", T::PHI)
+            .with_const("inf", T::INFINITY)
+            .with_const("nan", T::NAN)
+    }
+}
+
+impl<T: Number + Sign, const N: usize> ExprEnv<T, N> {
+    /// Add sign functions (abs, sign), along with their derivatives
+    pub fn with_sign(self) -> Self {
+        self.with_checked_func("abs", |[x]: [T; 1]| x.abs())
+            .with_func("sign", |[x]: [T; 1]| x.sign())
+            .with_derivative("abs", ["x"], ["sign(x)"])
+            .with_derivative("sign", ["x"], ["0"])
+    }
+}
+
+impl<T: Number + Rounding, const N: usize> ExprEnv<T, N> {
+    /// Add rounding functions (floor, ceil, round, trunc), along with their derivatives, which are
+    /// 0 away from the steps
+    pub fn with_rounding(self) -> Self {
+        self.with_func("floor", |[x]: [T; 1]| x.floor())
+            .with_func("ceil", |[x]: [T; 1]| x.ceil())
+            .with_func("round", |[x]: [T; 1]| x.round())
+            .with_func("trunc", |[x]: [T; 1]| x.trunc())
+            .with_derivative("floor", ["x"], ["0"])
+            .with_derivative("ceil", ["x"], ["0"])
+            .with_derivative("round", ["x"], ["0"])
+            .with_derivative("trunc", ["x"], ["0"])
+    }
+}
+
+impl<T: Number + Roots, const N: usize> ExprEnv<T, N> {
+    /// Add root functions (sqrt, cbrt, hypot), along with their derivatives
+    pub fn with_roots(self) -> Self {
+        self.with_func("sqrt", |[x]: [T; 1]| x.sqrt())
+            .with_func("cbrt", |[x]: [T; 1]| x.cbrt())
+            .with_func("hypot", |[x, y]: [T; 2]| x.hypot(y))
+            .with_derivative("sqrt", ["x"], ["1 / (2 * sqrt(x))"])
+            .with_derivative("cbrt", ["x"], ["1 / (3 * cbrt(x) ^ 2)"])
+            .with_derivative("hypot", ["x", "y"], ["x / hypot(x, y)", "y / hypot(x, y)"])
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> ExprEnv<T, N> {
+    /// Add exponential and logarithmic functions (exp, ln, log10, log2, log), along with their
+    /// derivatives
+    pub fn with_logarithms(self) -> Self {
+        self.with_func("exp", |[x]: [T; 1]| x.exp())
+            .with_func("ln", |[x]: [T; 1]| x.ln())
+            .with_func("log10", |[x]: [T; 1]| x.log10())
+            .with_func("log2", |[x]: [T; 1]| x.log2())
+            .with_func("log", |[x, base]: [T; 2]| x.log(base))
+            .with_derivative("exp", ["x"], ["exp(x)"])
+            .with_derivative("ln", ["x"], ["1 / x"])
+            .with_derivative("log10", ["x"], ["1 / (x * ln(10))"])
+            .with_derivative("log2", ["x"], ["1 / (x * ln(2))"])
+            .with_derivative(
+                "log",
+                ["x", "base"],
+                ["1 / (x * ln(base))", "-ln(x) / (base * ln(base) ^ 2)"],
+            )
+    }
+}
+
+impl<T: Number + InverseTrig, const N: usize> ExprEnv<T, N> {
+    /// Add inverse trig functions (asin, acos, atan, atan2), along with their derivatives
+    pub fn with_inverse_trig(self) -> Self {
+        self.with_func("asin", |[x]: [T; 1]| x.asin())
+            .with_func("acos", |[x]: [T; 1]| x.acos())
+            .with_func("atan", |[x]: [T; 1]| x.atan())
+            .with_func("atan2", |[y, x]: [T; 2]| y.atan2(x))
+            .with_derivative("asin", ["x"], ["(1 - x ^ 2) ^ -0.5"])
+            .with_derivative("acos", ["x"], ["-(1 - x ^ 2) ^ -0.5"])
+            .with_derivative("atan", ["x"], ["1 / (1 + x ^ 2)"])
+            .with_derivative(
+                "atan2",
+                ["y", "x"],
+                ["x / (x ^ 2 + y ^ 2)", "-y / (x ^ 2 + y ^ 2)"],
+            )
+    }
+}
+
+impl<T: Number + Hyperbolic, const N: usize> ExprEnv<T, N> {
+    /// Add hyperbolic functions (sinh, cosh, tanh, asinh, acosh, atanh), along with their
+    /// derivatives
+    pub fn with_hyperbolic(self) -> Self {
+        self.with_func("sinh", |[x]: [T; 1]| x.sinh())
+            .with_func("cosh", |[x]: [T; 1]| x.cosh())
+            .with_func("tanh", |[x]: [T; 1]| x.tanh())
+            .with_func("asinh", |[x]: [T; 1]| x.asinh())
+            .with_func("acosh", |[x]: [T; 1]| x.acosh())
+            .with_func("atanh", |[x]: [T; 1]| x.atanh())
+            .with_derivative("sinh", ["x"], ["cosh(x)"])
+            .with_derivative("cosh", ["x"], ["sinh(x)"])
+            .with_derivative("tanh", ["x"], ["1 - tanh(x) ^ 2"])
+            .with_derivative("asinh", ["x"], ["(x ^ 2 + 1) ^ -0.5"])
+            .with_derivative("acosh", ["x"], ["(x ^ 2 - 1) ^ -0.5"])
+            .with_derivative("atanh", ["x"], ["1 / (1 - x ^ 2)"])
+    }
+}
+
+impl<T: Number + StdMath, const N: usize> ExprEnv<T, N> {
+    /// Add the full standard math library, including trig, inverse trig and hyperbolic functions.
+    /// Every function has derivatives except the variadic min and max.
+    ///
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::Expr, env::ExprEnv};
+    ///
+    /// let env = ExprEnv::new(["x"]).with_std_math();
+    /// let expr = Expr::compile_env("max(sqrt(x), log(x, 2), abs(-1))", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[16.0]).unwrap(), 4.0);
+    /// ```
+    pub fn with_std_math(self) -> Self {
+        self.with_min_max()
+            .with_sign()
+            .with_rounding()
+            .with_roots()
+            .with_logarithms()
+            .with_trig()
+            .with_inverse_trig()
+            .with_hyperbolic()
+    }
 }
 
 impl<T: Number + Trig, const N: usize> ExprEnv<T, N> {
-    /// Add trig functions (sin, cos, tan)
+    /// Add trig functions (sin, cos, tan), along with their derivatives
     pub fn with_trig(self) -> Self {
         self.with_func("sin", |[x]: [T; 1]| x.sin())
             .with_func("cos", |[x]: [T; 1]| x.cos())
             .with_func("tan", |[x]: [T; 1]| x.tan())
+            .with_derivative("sin", ["x"], ["cos(x)"])
+            .with_derivative("cos", ["x"], ["-sin(x)"])
+            .with_derivative("tan", ["x"], ["1 / cos(x) ^ 2"])
+    }
+}
+
+/// An environment whose variable names are only known at runtime, for compiling a
+/// [`DynExpr`](crate::expr::DynExpr)
+///
+/// Example:
+/// ```
+/// use crunch_eval::{expr::DynExpr, env::DynEnv};
+///
+/// let names = vec!["price".to_owned(), "quantity".to_owned()];
+/// let env = DynEnv::new(names).with(|env| env.with_min_max());
+/// let expr = DynExpr::compile_env("min(price * quantity, 100)", env).unwrap();
+/// assert_eq!(expr.evaluate(&[12.5, 4.0]).unwrap(), 50.0);
+/// ```
+pub struct DynEnv<T: Number> {
+    pub(crate) env: ExprEnv<T, 0>,
+}
+
+impl<T: Number> DynEnv<T> {
+    /// Create an environment with the given variable names, whose values are passed in the same
+    /// order when evaluating
+    pub fn new(var_names: Vec<String>) -> DynEnv<T> {
+        let mut env = ExprEnv::default();
+        env.named_tokens.extend(
+            var_names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| (name.clone(), Token::Value(Value::Variable(index)))),
+        );
+        env.var_names = var_names;
+        DynEnv { env }
+    }
+
+    /// Add functions, constants, operators and modes using the builder methods of [`ExprEnv`]
+    pub fn with(mut self, build: impl FnOnce(ExprEnv<T, 0>) -> ExprEnv<T, 0>) -> Self {
+        self.env = build(self.env);
+        self
     }
 }
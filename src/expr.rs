@@ -1,12 +1,51 @@
+use std::{
+    cell::OnceCell,
+    fmt::{self, Debug, Display},
+    rc::Rc,
+};
+
 use crate::{
-    compiler::ExpressionCompiler, env::ExprEnv, parser::ParserError, EvalError, Number, Value,
+    ast::Node,
+    compiler::{compile_node, ExpressionCompiler},
+    diff::{differentiate, DerivativeError},
+    dual::{to_dual, Dual},
+    env::{DynEnv, ExprEnv},
+    number::{FloatMode, Logarithm},
+    parser::ParserError,
+    simplify::{simplify, Strictness},
+    vars::{lookup, VarSource},
+    EvalError, Number, Value,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 /// A compiled expression which evaluates to the numeric type T and requires N variable values to evaluate
-pub struct Expr<T: Number, const N: usize>(Value<T, N>);
+pub struct Expr<T: Number, const N: usize> {
+    pub(crate) value: Value<T>,
+    /// The environment compiled with, kept for differentiation
+    env: Rc<ExprEnv<T, 0>>,
+    /// The value re-instantiated with dual numbers by the first call to
+    /// [`evaluate_with_gradient`](Self::evaluate_with_gradient)
+    gradient: OnceCell<Result<Value<Dual<T, N>>, DerivativeError>>,
+}
+
+impl<T: Number, const N: usize> Debug for Expr<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Expr")
+            .field("value", &self.value)
+            .field("var_names", &self.env.var_names())
+            .finish_non_exhaustive()
+    }
+}
 
 impl<T: Number, const N: usize> Expr<T, N> {
+    fn new(value: Value<T>, env: Rc<ExprEnv<T, 0>>) -> Self {
+        Expr {
+            value,
+            env,
+            gradient: OnceCell::new(),
+        }
+    }
+
     /// Compile an expression from a string-convertible type.
     /// Example:
     /// ```
@@ -16,13 +55,132 @@ impl<T: Number, const N: usize> Expr<T, N> {
     /// let expr = Expr::compile_env(expr, Default::default()).unwrap();
     /// let value: f32 = expr.evaluate(&[]).unwrap();
     /// ```
-    pub fn compile_env(s: impl Into<String>, env: ExprEnv<T, N>) -> Result<Expr<T, N>, ParserError> {
-        ExpressionCompiler::compile(s, env).map(|e| Expr(e))
+    pub fn compile_env(
+        s: impl Into<String>,
+        env: ExprEnv<T, N>,
+    ) -> Result<Expr<T, N>, ParserError> {
+        let value = ExpressionCompiler::compile(s, &env)?;
+        Ok(Expr::new(value, Rc::new(env.erase())))
     }
 
     /// Evaluate the expression by supplying its variable values
     pub fn evaluate(&self, vars: &[T; N]) -> Result<T, EvalError> {
-        self.0.evaluate(vars)
+        check_vars(&self.env, vars)?;
+        self.value.evaluate(vars)
+    }
+
+    /// Evaluate the expression by looking up its variable values by name, from a map or a struct
+    /// deriving [`ExprVars`](crate::vars::ExprVars)
+    /// Example:
+    /// ```
+    /// use std::collections::HashMap;
+    /// use crunch_eval::{env::ExprEnv, expr::Expr, EvalError};
+    ///
+    /// let expr = Expr::compile_env("x - y", ExprEnv::new(["x", "y"])).unwrap();
+    /// let vars = HashMap::from([("y", 2), ("x", 5)]);
+    /// assert_eq!(expr.evaluate_with(&vars).unwrap(), 3);
+    ///
+    /// let err = expr.evaluate_with(&HashMap::from([("x", 5)])).unwrap_err();
+    /// assert!(matches!(err, EvalError::MissingVariable(name) if name == "y"));
+    /// ```
+    pub fn evaluate_with<S: VarSource<T> + ?Sized>(&self, vars: &S) -> Result<T, EvalError> {
+        let mut values = [T::default(); N];
+        lookup(vars, self.env.var_names(), &mut values)?;
+        self.evaluate(&values)
+    }
+
+    /// The syntax tree of the compiled expression, for inspection with a
+    /// [`Visitor`](crate::ast::Visitor) or rewriting with a [`Fold`](crate::ast::Fold)
+    /// Example:
+    /// ```
+    /// use crunch_eval::{ast::{BinaryOp, Node}, env::ExprEnv, expr::Expr};
+    ///
+    /// let env = ExprEnv::new(["x"]).with_trig();
+    /// let expr = Expr::compile_env("sin(x) * 2", env).unwrap();
+    /// let sin_x = Node::Call("sin".into(), vec![Node::Variable(0, "x".into())]);
+    /// assert_eq!(
+    ///     expr.ast(),
+    ///     Node::Binary(BinaryOp::Mul, Box::new([sin_x, Node::Constant(2.0)]))
+    /// );
+    /// ```
+    pub fn ast(&self) -> Node<T> {
+        self.value.to_node(self.env.var_names())
+    }
+
+    /// Compile a syntax tree, such as one rewritten with a [`Fold`](crate::ast::Fold), against
+    /// an environment. Variables, operators and functions are looked up by name as when
+    /// compiling source, and errors have empty spans as there is no source to point to.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{ast::{BinaryOp, Fold, Node}, env::ExprEnv, expr::Expr};
+    ///
+    /// struct DivToMul;
+    ///
+    /// impl Fold<f64> for DivToMul {
+    ///     fn fold_binary(&mut self, op: BinaryOp, left: Node<f64>, right: Node<f64>) -> Node<f64> {
+    ///         let (left, right) = (self.fold(left), self.fold(right));
+    ///         match (op, right) {
+    ///             (BinaryOp::Div, Node::Constant(c)) => {
+    ///                 Node::Binary(BinaryOp::Mul, Box::new([left, Node::Constant(1.0 / c)]))
+    ///             }
+    ///             (op, right) => Node::Binary(op, Box::new([left, right])),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let expr = Expr::compile_env("sqrt(x / 4)", ExprEnv::new(["x"]).with_std_math()).unwrap();
+    /// let node = DivToMul.fold(expr.ast());
+    /// let expr = Expr::from_ast(node, ExprEnv::new(["x"]).with_std_math()).unwrap();
+    /// assert_eq!(expr.to_string(), "sqrt(x * 0.25)");
+    /// assert_eq!(expr.evaluate(&[16.0]).unwrap(), 2.0);
+    /// ```
+    pub fn from_ast(node: Node<T>, env: ExprEnv<T, N>) -> Result<Expr<T, N>, ParserError> {
+        let value = compile_node(node, &env)?;
+        Ok(Expr::new(value, Rc::new(env.erase())))
+    }
+
+    /// Names of the variables the expression references, in the order they were given to the
+    /// environment. Variables only used in a branch of a conditional are included.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{env::ExprEnv, expr::Expr};
+    ///
+    /// let env = ExprEnv::<f64, 3>::new(["x", "y", "z"]).with_std_math();
+    /// let expr = Expr::compile_env("max(z, sqrt(x)) * z", env).unwrap();
+    /// assert_eq!(expr.used_variables(), ["x", "z"]);
+    /// assert_eq!(expr.used_functions(), ["max", "sqrt"]);
+    /// ```
+    pub fn used_variables(&self) -> Vec<&str> {
+        used_variable_names(&self.value, self.env.var_names())
+    }
+
+    /// Names of the functions the expression calls, in alphabetical order
+    pub fn used_functions(&self) -> Vec<&str> {
+        self.value.used_functions().into_iter().collect()
+    }
+
+    /// The partial derivative of the expression with respect to the named variable. Every built-in
+    /// operator can be differentiated except the bitwise ones, and for integers `/`, `%` and `^`
+    /// with an exponent depending on the variable. Functions can be differentiated if they were
+    /// given derivatives with [`ExprEnv::with_derivative`], as the standard math functions are.
+    /// `^` with an exponent depending on the variable calls the environment's `ln`, as added by
+    /// [`ExprEnv::with_logarithms`]. Conditionals are differentiated branch by branch. New
+    /// operations follow the environment's integer and float modes.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{env::ExprEnv, expr::Expr};
+    ///
+    /// let env = ExprEnv::new(["x", "y"]).with_trig();
+    /// let expr = Expr::compile_env("x ^ 3 * y + sin(y)", env).unwrap();
+    /// let dx = expr.derivative("x").unwrap();
+    /// assert_eq!(dx.to_string(), "3.0 * x ^ 2.0 * y");
+    /// assert_eq!(dx.evaluate(&[2.0, 1.0]).unwrap(), 12.0);
+    /// let dy = expr.derivative("y").unwrap();
+    /// assert_eq!(dy.to_string(), "x ^ 3.0 + cos(y)");
+    /// ```
+    pub fn derivative(&self, var_name: &str) -> Result<Expr<T, N>, DerivativeError> {
+        let value = derivative(&self.value, &self.env, var_name)?;
+        Ok(Expr::new(value, self.env.clone()))
     }
 
     /// Evaluate by passing 0 for all variable values
@@ -32,7 +190,79 @@ impl<T: Number, const N: usize> Expr<T, N> {
 
     /// Inline operations on constant values to speed up evaluation
     pub fn flatten(self) -> Result<Expr<T, N>, EvalError> {
-        Ok(Expr(self.0.flatten()?))
+        Ok(Expr::new(self.value.flatten()?, self.env))
+    }
+
+    /// Flatten the expression, then remove operations which don't change their operand like
+    /// `x * 1`, replace operations with a known result like `x ^ 0`, combine constants across
+    /// chains of `+` or `*` like `(x + 2) + 3`, and remove double negation. The strictness
+    /// decides which of these may be applied where they could change a result.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{env::ExprEnv, expr::Expr, simplify::Strictness};
+    ///
+    /// let env = ExprEnv::<f64, 2>::new(["x", "y"]);
+    /// let expr = Expr::compile_env("(2 + x) + 3 - 0 * -(-y)", env).unwrap();
+    /// let exact = expr.clone().simplify(Strictness::Exact).unwrap();
+    /// assert_eq!(exact.to_string(), "2.0 + x + 3.0 - 0.0 * y");
+    /// let relaxed = expr.simplify(Strictness::Relaxed).unwrap();
+    /// assert_eq!(relaxed.to_string(), "x + 5.0");
+    ///
+    /// let env = ExprEnv::<i32, 2>::new(["x", "y"]);
+    /// let expr = Expr::compile_env("(2 + x) + 3 - 0 * y", env).unwrap();
+    /// let exact = expr.simplify(Strictness::Exact).unwrap();
+    /// assert_eq!(exact.to_string(), "x + 5");
+    /// assert_eq!(exact.evaluate(&[1, 2]).unwrap(), 6);
+    /// ```
+    pub fn simplify(self, strictness: Strictness) -> Result<Expr<T, N>, EvalError> {
+        let value = simplify(self.value.flatten()?, self.env.modes(), strictness);
+        Ok(Expr::new(value, self.env))
+    }
+}
+
+impl<T: Number + Logarithm, const N: usize> Expr<T, N> {
+    /// Evaluate the expression along with its gradient, the partial derivative with respect to
+    /// each variable, in a single pass using [`Dual`] numbers. The expression can be
+    /// differentiated wherever [`derivative`](Self::derivative) could, and fails with
+    /// [`EvalError::Derivative`] otherwise. The expression is re-instantiated with dual numbers on
+    /// the first call, and the result kept for later calls.
+    /// Example:
+    /// ```
+    /// use crunch_eval::{env::ExprEnv, expr::Expr};
+    ///
+    /// let env = ExprEnv::new(["x", "y"]).with_trig();
+    /// let expr = Expr::compile_env("x ^ 2 * y + cos(y)", env).unwrap();
+    /// let (value, gradient) = expr.evaluate_with_gradient(&[3.0, 0.0]).unwrap();
+    /// assert_eq!(value, 1.0);
+    /// assert_eq!(gradient, [0.0, 9.0]);
+    /// ```
+    pub fn evaluate_with_gradient(&self, vars: &[T; N]) -> Result<(T, [T; N]), EvalError> {
+        let value = self
+            .gradient
+            .get_or_init(|| to_dual(&self.value, &self.env))
+            .as_ref()
+            .map_err(|err| EvalError::Derivative(err.clone()))?;
+        check_vars(&self.env, vars)?;
+        let vars: [Dual<T, N>; N] = std::array::from_fn(|i| Dual::variable(vars[i], i));
+        let result = value.evaluate(&vars)?;
+        Ok((result.value, result.gradient))
+    }
+}
+
+/// Prints the expression as source text which compiles to an equivalent expression
+///
+/// Example:
+/// ```
+/// use crunch_eval::{env::ExprEnv, expr::Expr};
+///
+/// let env = ExprEnv::<i32, 1>::new(["x"]);
+/// let expr = Expr::compile_env("((x)) * (2 + 3 * 4) - (x - 1)", env).unwrap();
+/// assert_eq!(expr.to_string(), "x * (2 + 3 * 4) - (x - 1)");
+/// assert_eq!(expr.flatten().unwrap().to_string(), "x * 14 - (x - 1)");
+/// ```
+impl<T: Number, const N: usize> Display for Expr<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.ast(), f)
     }
 }
 
@@ -47,3 +277,154 @@ impl<T: Number> Expr<T, 0> {
         self.evaluate(&[])
     }
 }
+
+/// A compiled expression whose number of variables is only known at runtime. Variable values are
+/// passed as a slice, whose length is checked against the environment it was compiled with.
+#[derive(Clone)]
+pub struct DynExpr<T: Number> {
+    pub(crate) value: Value<T>,
+    env: Rc<ExprEnv<T, 0>>,
+}
+
+impl<T: Number> Debug for DynExpr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynExpr")
+            .field("value", &self.value)
+            .field("var_names", &self.env.var_names())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Number> DynExpr<T> {
+    /// Compile an expression using an environment with runtime variable names
+    pub fn compile_env(s: impl Into<String>, env: DynEnv<T>) -> Result<DynExpr<T>, ParserError> {
+        let value = ExpressionCompiler::compile(s, &env.env)?;
+        Ok(DynExpr {
+            value,
+            env: Rc::new(env.env),
+        })
+    }
+
+    /// Evaluate the expression by supplying its variable values, in the order their names were
+    /// given to the environment
+    /// Example:
+    /// ```
+    /// use crunch_eval::{expr::DynExpr, env::DynEnv, EvalError};
+    ///
+    /// let env = DynEnv::new(vec!["a".into(), "b".into()]);
+    /// let expr = DynExpr::compile_env("a - b", env).unwrap();
+    /// assert_eq!(expr.evaluate(&[5, 3]).unwrap(), 2);
+    /// let err = expr.evaluate(&[5]).unwrap_err();
+    /// assert!(matches!(err, EvalError::VariableCount { expected: 2, given: 1 }));
+    /// ```
+    pub fn evaluate(&self, vars: &[T]) -> Result<T, EvalError> {
+        if vars.len() != self.var_count() {
+            return Err(EvalError::VariableCount {
+                expected: self.var_count(),
+                given: vars.len(),
+            });
+        }
+        check_vars(&self.env, vars)?;
+        self.value.evaluate(vars)
+    }
+
+    /// Evaluate the expression by looking up its variable values by name, from a map or a struct
+    /// deriving [`ExprVars`](crate::vars::ExprVars)
+    pub fn evaluate_with<S: VarSource<T> + ?Sized>(&self, vars: &S) -> Result<T, EvalError> {
+        let mut values = vec![T::default(); self.var_count()];
+        lookup(vars, self.env.var_names(), &mut values)?;
+        check_vars(&self.env, &values)?;
+        self.value.evaluate(&values)
+    }
+
+    /// The syntax tree of the compiled expression
+    pub fn ast(&self) -> Node<T> {
+        self.value.to_node(self.env.var_names())
+    }
+
+    /// Compile a syntax tree against an environment, as for [`Expr::from_ast`]
+    pub fn from_ast(node: Node<T>, env: DynEnv<T>) -> Result<DynExpr<T>, ParserError> {
+        let value = compile_node(node, &env.env)?;
+        Ok(DynExpr {
+            value,
+            env: Rc::new(env.env),
+        })
+    }
+
+    /// Names of the variables the expression references, in the order they were given to the
+    /// environment. Variables only used in a branch of a conditional are included.
+    pub fn used_variables(&self) -> Vec<&str> {
+        used_variable_names(&self.value, self.env.var_names())
+    }
+
+    /// Names of the functions the expression calls, in alphabetical order
+    pub fn used_functions(&self) -> Vec<&str> {
+        self.value.used_functions().into_iter().collect()
+    }
+
+    /// The partial derivative of the expression with respect to the named variable, as for
+    /// [`Expr::derivative`]
+    pub fn derivative(&self, var_name: &str) -> Result<DynExpr<T>, DerivativeError> {
+        Ok(DynExpr {
+            value: derivative(&self.value, &self.env, var_name)?,
+            env: self.env.clone(),
+        })
+    }
+
+    /// The number of variable values the expression must be evaluated with
+    pub fn var_count(&self) -> usize {
+        self.env.var_names().len()
+    }
+
+    /// Inline operations on constant values to speed up evaluation
+    pub fn flatten(self) -> Result<DynExpr<T>, EvalError> {
+        Ok(DynExpr {
+            value: self.value.flatten()?,
+            env: self.env,
+        })
+    }
+
+    /// Flatten and simplify the expression, as for [`Expr::simplify`]
+    pub fn simplify(self, strictness: Strictness) -> Result<DynExpr<T>, EvalError> {
+        Ok(DynExpr {
+            value: simplify(self.value.flatten()?, self.env.modes(), strictness),
+            env: self.env,
+        })
+    }
+}
+
+/// Prints the expression as source text which compiles to an equivalent expression
+impl<T: Number> Display for DynExpr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.ast(), f)
+    }
+}
+
+fn derivative<T: Number>(
+    value: &Value<T>,
+    env: &ExprEnv<T, 0>,
+    var_name: &str,
+) -> Result<Value<T>, DerivativeError> {
+    let var = env
+        .var_names()
+        .iter()
+        .position(|name| name == var_name)
+        .ok_or_else(|| DerivativeError::UnknownVariable(var_name.to_owned()))?;
+    differentiate(env, value, var)
+}
+
+fn used_variable_names<'a, T: Number>(value: &Value<T>, var_names: &'a [String]) -> Vec<&'a str> {
+    value
+        .used_variables()
+        .into_iter()
+        .map(|index| var_names[index].as_str())
+        .collect()
+}
+
+/// Under [`FloatMode::Strict`] variables must be finite, just as every result must be
+fn check_vars<T: Number>(env: &ExprEnv<T, 0>, vars: &[T]) -> Result<(), EvalError> {
+    if env.modes().float == FloatMode::Strict && !vars.iter().all(T::is_finite) {
+        return Err(EvalError::NonFinite);
+    }
+    Ok(())
+}
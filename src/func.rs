@@ -1,6 +1,13 @@
-use std::{fmt::Debug, marker::PhantomData, rc::Rc};
+use std::{
+    convert::Infallible,
+    error::Error,
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    rc::Rc,
+};
 
-use crate::{EvalError, Number, Value};
+use crate::{number::finite, parser::Span, EvalError, Number, Value};
 
 pub trait CustomFunc<T: Number, const N: usize, const A: usize>: 'static + Fn([T; A]) -> T {}
 impl<T: Number, const N: usize, const A: usize, F: 'static + Fn([T; A]) -> T> CustomFunc<T, N, A>
@@ -8,50 +15,205 @@ impl<T: Number, const N: usize, const A: usize, F: 'static + Fn([T; A]) -> T> Cu
 {
 }
 
+/// A custom function which can fail, returning an error which is surfaced as [`EvalError::Custom`]
+pub trait FallibleFunc<T: Number, E, const N: usize, const A: usize>:
+    'static + Fn([T; A]) -> Result<T, E>
+{
+}
+impl<T: Number, E, const N: usize, const A: usize, F: 'static + Fn([T; A]) -> Result<T, E>>
+    FallibleFunc<T, E, N, A> for F
+{
+}
+
+/// An error returned by a custom function
+pub type CustomError = Box<dyn Error + Send + Sync>;
+
+/// The number of arguments a function accepts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arity {
+    pub min: usize,
+    /// The maximum number of arguments, or `None` if there is no limit
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub fn exactly(args: usize) -> Arity {
+        Arity {
+            min: args,
+            max: Some(args),
+        }
+    }
+
+    /// The arity accepting any number of arguments in the range. Panics if the range is empty,
+    /// like `0..0` or `5..3`, as no call could satisfy it.
+    pub fn from_range(range: impl RangeBounds<usize>) -> Arity {
+        let min = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let max = match range.end_bound() {
+            Bound::Included(&n) if n >= min => Some(n),
+            Bound::Excluded(&n) if n > min => Some(n - 1),
+            Bound::Unbounded => None,
+            _ => panic!("no number of arguments is in the range given"),
+        };
+        Arity { min, max }
+    }
+
+    pub fn contains(&self, args: usize) -> bool {
+        args >= self.min && self.max.is_none_or(|max| args <= max)
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{max} {}", plural(max)),
+            Some(max) => write!(f, "{} to {max} {}", self.min, plural(max)),
+            None => write!(f, "at least {} {}", self.min, plural(self.min)),
+        }
+    }
+}
+
+type BoxedFunc<T> = Rc<dyn Fn(&[T]) -> Result<T, EvalError>>;
+
+/// The partial derivatives of a function with respect to each of its arguments, as source text
+/// in terms of the parameter names. They are compiled when an expression is differentiated, so
+/// they can call functions whose own derivatives are added later.
+#[derive(Clone, Debug)]
+pub(crate) struct Partials {
+    pub params: Vec<String>,
+    pub sources: Vec<String>,
+    /// The namespace the function was added under, whose members the sources refer to unqualified
+    pub namespace: String,
+}
+
 #[derive(Clone)]
-pub(crate) struct Function<T: Number, const N: usize> {
-    func: Rc<dyn Fn(&[T]) -> T>,
-    pub args: usize,
+pub(crate) struct Function<T> {
+    func: BoxedFunc<T>,
+    pub name: Rc<str>,
+    pub arity: Arity,
+    pub partials: Option<Rc<Partials>>,
     num: PhantomData<T>,
 }
 
-impl<T: Number, const N: usize> Debug for Function<T, N> {
+impl<T> Debug for Function<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("Function")
+        write!(f, "Function({})", self.name)
     }
 }
 
-impl<T: Number, const N: usize> Function<T, N> {
-    pub(crate) fn new<const A: usize, F: CustomFunc<T, N, A>>(f: F) -> Function<T, N> {
+impl<T: Number> Function<T> {
+    pub(crate) fn new<const A: usize, F: 'static + Fn([T; A]) -> T>(
+        name: &str,
+        f: F,
+    ) -> Function<T> {
+        Self::new_fallible::<Infallible, A, _>(name, move |args| Ok(f(args)))
+    }
+
+    pub(crate) fn new_fallible<
+        E: Into<CustomError>,
+        const A: usize,
+        F: 'static + Fn([T; A]) -> Result<T, E>,
+    >(
+        name: &str,
+        f: F,
+    ) -> Function<T> {
+        Self::new_checked::<A, _>(name, move |args| {
+            f(args).map_err(|e| EvalError::Custom(e.into()))
+        })
+    }
+
+    /// Create a function whose errors are already evaluation errors
+    pub(crate) fn new_checked<const A: usize, F: 'static + Fn([T; A]) -> Result<T, EvalError>>(
+        name: &str,
+        f: F,
+    ) -> Function<T> {
         let boxed = Rc::new(move |args: &[T]| {
             let args: [T; A] = args.try_into().expect("Incorrect argument count");
             f(args)
         });
         Function {
             func: boxed,
-            args: A,
-            num: PhantomData::default(),
+            name: name.into(),
+            arity: Arity::exactly(A),
+            partials: None,
+            num: PhantomData,
+        }
+    }
+
+    pub(crate) fn new_variadic<F: 'static + Fn(&[T]) -> T>(
+        name: &str,
+        arity: Arity,
+        f: F,
+    ) -> Function<T> {
+        Self::new_checked_variadic(name, arity, move |args| Ok(f(args)))
+    }
+
+    /// Create a function accepting a variable number of arguments, whose errors are already
+    /// evaluation errors
+    pub(crate) fn new_checked_variadic<F: 'static + Fn(&[T]) -> Result<T, EvalError>>(
+        name: &str,
+        arity: Arity,
+        f: F,
+    ) -> Function<T> {
+        Function {
+            func: Rc::new(f),
+            name: name.into(),
+            arity,
+            partials: None,
+            num: PhantomData,
+        }
+    }
+
+    /// Call the function, without the context added when it is invoked from an expression
+    pub(crate) fn call(&self, args: &[T]) -> Result<T, EvalError> {
+        (self.func)(args)
+    }
+
+    /// Wrap the function so that returning NaN or an infinity is an error
+    pub(crate) fn finite(self) -> Function<T> {
+        let func = self.func;
+        Function {
+            func: Rc::new(move |args: &[T]| finite(func(args)?)),
+            ..self
         }
     }
 }
 
 #[derive(Clone, Debug)]
-pub(crate) struct FunctionInvoke<T: Number, const N: usize> {
-    pub func: Function<T, N>,
-    pub args: Vec<Value<T, N>>,
+pub(crate) struct FunctionInvoke<T> {
+    pub func: Function<T>,
+    pub args: Vec<Value<T>>,
+    pub span: Span,
 }
 
-impl<T: Number, const N: usize> FunctionInvoke<T, N> {
-    pub fn new(func: Function<T, N>, args: Vec<Value<T, N>>) -> FunctionInvoke<T, N> {
-        FunctionInvoke { func, args }
+impl<T: Number> FunctionInvoke<T> {
+    pub fn new(func: Function<T>, args: Vec<Value<T>>, span: Span) -> FunctionInvoke<T> {
+        FunctionInvoke { func, args, span }
     }
 
-    pub fn invoke(&self, vars: &[T; N]) -> Result<T, EvalError> {
+    pub fn invoke(&self, vars: &[T]) -> Result<T, EvalError> {
+        // Most functions take few arguments, which are gathered on the stack
+        const INLINE: usize = 4;
+        if self.args.len() <= INLINE {
+            let mut evaluated = [T::default(); INLINE];
+            for (slot, arg) in evaluated.iter_mut().zip(&self.args) {
+                *slot = arg.evaluate(vars)?;
+            }
+            return self.call(&evaluated[..self.args.len()]);
+        }
         let evaluated = self
             .args
             .iter()
             .map(|v| v.evaluate(vars))
             .collect::<Result<Vec<T>, EvalError>>()?;
-        Ok((self.func.func)(&evaluated))
+        self.call(&evaluated)
+    }
+
+    fn call(&self, args: &[T]) -> Result<T, EvalError> {
+        (self.func.func)(args).map_err(|e| e.at(self.span, &self.func.name, args))
     }
 }
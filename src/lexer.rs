@@ -0,0 +1,113 @@
+/// A range of byte offsets into the source string
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The span covering both this span and a later one
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            start: self.start,
+            end: end.end,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LexemeKind {
+    Number,
+    Name,
+    Symbol,
+}
+
+/// A single token of source text, such as a number, a name, an operator or punctuation
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Lexeme<'a> {
+    pub kind: LexemeKind,
+    pub text: &'a str,
+    pub span: Span,
+}
+
+/// Symbols made of more than one character, which must not be split apart
+const COMPOUND_SYMBOLS: [&str; 8] = ["==", "!=", "<=", ">=", "&&", "||", "<<", ">>"];
+
+/// Split source text into lexemes. Whitespace separates lexemes and is otherwise discarded.
+pub(crate) fn lex(source: &str) -> Vec<Lexeme<'_>> {
+    let mut lexemes = Vec::new();
+    let mut pos = 0;
+    while let Some(c) = source[pos..].chars().next() {
+        let rest = &source[pos..];
+        let (kind, len) = if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        } else if c.is_ascii_digit() {
+            (LexemeKind::Number, number_len(rest))
+        } else if is_name_start(c) {
+            (LexemeKind::Name, name_len(rest))
+        } else {
+            let len = COMPOUND_SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .map_or(c.len_utf8(), |s| s.len());
+            (LexemeKind::Symbol, len)
+        };
+        lexemes.push(Lexeme {
+            kind,
+            text: &rest[..len],
+            span: Span {
+                start: pos,
+                end: pos + len,
+            },
+        });
+        pos += len;
+    }
+    lexemes
+}
+
+/// Length in bytes of the longest prefix of `s` whose characters all match `filter`
+fn prefix_len(s: &str, filter: fn(char) -> bool) -> usize {
+    s.find(|c| !filter(c)).unwrap_or(s.len())
+}
+
+/// Length of a number literal at the start of `s`. This covers radix-prefixed integers like
+/// `0xFF`, decimals with an optional exponent like `6.02e23`, and `_` digit separators.
+fn number_len(s: &str) -> usize {
+    let digits = |s: &str| prefix_len(s, |c| c.is_ascii_digit() || c == '_');
+    if matches!(s.get(..2), Some("0x" | "0X" | "0b" | "0B" | "0o" | "0O")) {
+        // Take any alphanumeric characters, so invalid digits are reported as part of the literal
+        return 2 + prefix_len(&s[2..], |c| c.is_ascii_alphanumeric() || c == '_');
+    }
+    let mut len = digits(s);
+    if s[len..].starts_with('.') {
+        len += 1 + digits(&s[len + 1..]);
+    }
+    let exponent = &s[len..];
+    if exponent.starts_with(['e', 'E']) {
+        let sign = usize::from(exponent[1..].starts_with(['+', '-']));
+        if exponent[1 + sign..].starts_with(|c: char| c.is_ascii_digit()) {
+            len += 1 + sign + digits(&exponent[1 + sign..]);
+        }
+    }
+    len
+}
+
+/// Names are ASCII, starting with a letter or `_`, so that they read the same everywhere
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+/// Length of a name at the start of `s`, made of dot-separated identifiers like `stats.mean`
+fn name_len(s: &str) -> usize {
+    let mut len = 0;
+    loop {
+        len += prefix_len(&s[len..], |c| c.is_ascii_alphanumeric() || c == '_');
+        let mut rest = s[len..].chars();
+        if rest.next() == Some('.') && rest.next().is_some_and(is_name_start) {
+            len += 1;
+        } else {
+            return len;
+        }
+    }
+}
@@ -7,81 +7,334 @@
 //! let val: f64 = expr.evaluate_blank().unwrap();
 //! assert_eq!(val, 2.0);
 //! ```
+//!
+//! ## Matching evaluation errors
+//!
+//! An error raised by an operator or function is wrapped in [`EvalError::Context`], which records
+//! the operation, its operands and where it is in the source. This is a breaking change from
+//! earlier versions: matching the result of evaluation against a variant like
+//! `Err(EvalError::DivideByZero)` no longer succeeds. Match against [`EvalError::root`] instead,
+//! which gives the underlying error whether or not it has context:
+//! ```
+//! use crunch_eval::{expr::Expr, EvalError};
+//!
+//! let err = Expr::<i32, 0>::compile("1 / 0").unwrap().evaluate_blank().unwrap_err();
+//! assert!(!matches!(err, EvalError::DivideByZero));
+//! assert!(matches!(err.root(), EvalError::DivideByZero));
+//! ```
+//!
+//! ## Custom number types
+//!
+//! [`Number`] is implemented for every type with the traits it requires, which
+//! the primitive integers and floats all have. Besides the arithmetic operator traits, a type of
+//! your own now also needs these traits from [`number`], which is a breaking change from earlier
+//! versions:
+//! - [`ParseRadix`](number::ParseRadix), to read `0x`, `0o` and `0b` literals. Return
+//!   [`LiteralError::Invalid`](number::LiteralError::Invalid) if the type has no such literals.
+//! - [`Boolean`](number::Boolean), to convert to and from the results of comparison and logical
+//!   operators.
+//! - [`OverflowOps`](number::OverflowOps), for [`IntegerMode::Wrapping`] and
+//!   [`IntegerMode::Saturating`]. Types which cannot overflow can forward to the ordinary
+//!   operations.
+//! - [`FloatOps`](number::FloatOps), for [`FloatMode`]. Set `FLOAT` to `false` for types whose
+//!   division truncates.
+//!
+//! [`IntegerMode::Wrapping`]: number::IntegerMode::Wrapping
+//! [`IntegerMode::Saturating`]: number::IntegerMode::Saturating
+//! [`FloatMode`]: number::FloatMode
 
+use ast::{BinaryOp, UnaryOp};
 use func::*;
 use number::Number;
-use std::fmt::Debug;
+use parser::Span;
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Debug, Display},
+};
 
+// Lets code generated by the derive macros name this crate from within it
+extern crate self as crunch_eval;
+
+pub mod ast;
 pub mod compiler;
+pub mod diff;
+pub mod dual;
 pub mod env;
 pub mod expr;
-mod func;
+pub mod func;
+mod lexer;
 pub mod number;
-mod parser;
+pub mod parser;
+pub mod simplify;
 #[cfg(test)]
 mod tests;
+pub mod vars;
 
+/// An error encountered while evaluating an expression. Errors raised by an operation are
+/// wrapped in [`EvalError::Context`], so match against [`root`](EvalError::root) to find the kind
+/// of error.
 #[derive(Debug)]
 pub enum EvalError {
     NegativeIntegerExponent,
     DivideByZero,
     Overflow,
+    /// An operation produced NaN or an infinity, or a variable was given one, under
+    /// [`FloatMode::Strict`](number::FloatMode::Strict)
+    NonFinite,
+    /// A [`DynExpr`](expr::DynExpr) was evaluated with the wrong number of variable values
+    VariableCount {
+        expected: usize,
+        given: usize,
+    },
+    /// No value was supplied for the named variable
+    MissingVariable(String),
+    /// An error returned by a custom function
+    Custom(CustomError),
+    /// A gradient was requested of an expression which can't be differentiated
+    Derivative(diff::DerivativeError),
+    /// An error raised by a specific operation in the expression, with details of where it occurred
+    Context(Box<ErrorContext>),
+}
+
+/// The location and inputs of the operation which caused an [`EvalError`]
+#[derive(Debug)]
+pub struct ErrorContext {
+    pub error: EvalError,
+    /// The subexpression which failed to evaluate
+    pub span: Span,
+    /// The operator symbol or function name
+    pub operation: String,
+    /// The operand values, formatted with `Debug`
+    pub operands: Vec<String>,
+}
+
+impl EvalError {
+    /// The underlying error, without its context
+    pub fn root(&self) -> &EvalError {
+        match self {
+            EvalError::Context(context) => context.error.root(),
+            _ => self,
+        }
+    }
+
+    /// Details of the operation which caused the error, if known
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            EvalError::Context(context) => Some(context),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn at<T: Debug>(self, span: Span, operation: &str, operands: &[T]) -> EvalError {
+        EvalError::Context(Box::new(ErrorContext {
+            error: self,
+            span,
+            operation: operation.to_owned(),
+            operands: operands.iter().map(|o| format!("{o:?}")).collect(),
+        }))
+    }
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::NegativeIntegerExponent => f.write_str("negative exponent in integer power"),
+            EvalError::DivideByZero => f.write_str("division by zero"),
+            EvalError::Overflow => f.write_str("arithmetic overflow"),
+            EvalError::NonFinite => f.write_str("non-finite result"),
+            EvalError::VariableCount { expected, given } => {
+                write!(
+                    f,
+                    "expected {expected} variable values but {given} were given"
+                )
+            }
+            EvalError::MissingVariable(name) => write!(f, "no value for variable `{name}`"),
+            EvalError::Custom(err) => Display::fmt(err, f),
+            EvalError::Derivative(err) => Display::fmt(err, f),
+            EvalError::Context(context) => write!(
+                f,
+                "{} in `{}` at offset {} (operands: {})",
+                context.error,
+                context.operation,
+                context.span.start,
+                context.operands.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EvalError::Custom(err) => Some(&**err),
+            EvalError::Derivative(err) => Some(err),
+            EvalError::Context(context) => Some(&context.error),
+            _ => None,
+        }
+    }
+}
+
+type BinaryFn<T> = fn(T, T) -> Result<T, EvalError>;
+type UnaryFn<T> = fn(T) -> Result<T, EvalError>;
+
+/// An operator, along with the function implementing it
+#[derive(Clone, Copy, Debug)]
+struct Operator<O, F> {
+    op: O,
+    func: F,
 }
 
 #[derive(Clone, Debug)]
-enum Value<T: Number, const N: usize> {
+enum Value<T> {
     Constant(T),
     Variable(usize),
-    BinaryOperation(fn(T, T) -> Result<T, EvalError>, Box<[Value<T, N>; 2]>),
-    UnaryOperation(fn(T) -> Result<T, EvalError>, Box<Value<T, N>>),
-    FunctionInvoke(FunctionInvoke<T, N>),
+    BinaryOperation(Operator<BinaryOp, BinaryFn<T>>, Box<[Value<T>; 2]>, Span),
+    /// `&&` or `||`, whose right operand is only evaluated if the left doesn't decide the result.
+    /// They are kept apart so that other binary operations needn't check.
+    ShortCircuit(Operator<BinaryOp, BinaryFn<T>>, Box<[Value<T>; 2]>, Span),
+    UnaryOperation(Operator<UnaryOp, UnaryFn<T>>, Box<Value<T>>, Span),
+    FunctionInvoke(Box<FunctionInvoke<T>>),
+    /// Condition, value if true, value if false. Only the chosen branch is evaluated.
+    Conditional(Box<[Value<T>; 3]>),
 }
 
-impl<T: Number, const N: usize> Value<T, N> {
-    fn flatten(self) -> Result<Value<T, N>, EvalError> {
+impl<T: Number> Value<T> {
+    /// A binary operation, which short-circuits if its operator does
+    fn binary(op: Operator<BinaryOp, BinaryFn<T>>, args: Box<[Value<T>; 2]>, span: Span) -> Self {
+        if op.op.short_circuits() {
+            Value::ShortCircuit(op, args, span)
+        } else {
+            Value::BinaryOperation(op, args, span)
+        }
+    }
+
+    fn flatten(self) -> Result<Value<T>, EvalError> {
         use Value::*;
         Ok(match self {
             Constant(_) | Variable(_) => self,
-            FunctionInvoke(crate::func::FunctionInvoke { func, args }) => {
-                FunctionInvoke(crate::func::FunctionInvoke {
-                    func,
-                    args: args
-                        .into_iter()
-                        .map(|a| a.flatten())
-                        .collect::<Result<Vec<Value<T, N>>, EvalError>>()?,
-                })
+            FunctionInvoke(mut invoke) => {
+                invoke.args = std::mem::take(&mut invoke.args)
+                    .into_iter()
+                    .map(|a| a.flatten())
+                    .collect::<Result<Vec<Value<T>>, EvalError>>()?;
+                FunctionInvoke(invoke)
             }
-            BinaryOperation(op, mut args) => {
+            BinaryOperation(op, mut args, span) | ShortCircuit(op, mut args, span) => {
                 let [left, right] = *args;
-                *args = [left.flatten()?, right.flatten()?];
-                if let [Value::Constant(a), Value::Constant(b)] = &*args {
-                    Value::Constant(op(*a, *b)?)
+                let left = left.flatten()?;
+                let right = match left {
+                    Value::Constant(a) => match op.op.short_circuit(a.to_bool()) {
+                        Some(result) => return Ok(Value::Constant(T::from_bool(result))),
+                        None => right.flatten()?,
+                    },
+                    // The right operand of `&&` or `||` may never be evaluated
+                    _ if op.op.short_circuits() => right.flatten_lazy(),
+                    _ => right.flatten()?,
+                };
+                *args = [left, right];
+                if let [Value::Constant(a), Value::Constant(b)] = *args {
+                    Value::Constant(
+                        (op.func)(a, b).map_err(|e| e.at(span, op.op.symbol(), &[a, b]))?,
+                    )
                 } else {
-                    BinaryOperation(op, args)
+                    Value::binary(op, args, span)
                 }
             }
-            UnaryOperation(op, mut arg) => {
+            UnaryOperation(op, mut arg, span) => {
                 *arg = (*arg).flatten()?;
-                if let Value::Constant(val) = &*arg {
-                    Value::Constant(op(*val)?)
+                if let Value::Constant(val) = *arg {
+                    Value::Constant((op.func)(val).map_err(|e| e.at(span, op.op.symbol(), &[val]))?)
                 } else {
-                    UnaryOperation(op, arg)
+                    UnaryOperation(op, arg, span)
+                }
+            }
+            Conditional(args) => {
+                let [condition, if_true, if_false] = *args;
+                match condition.flatten()? {
+                    Value::Constant(c) if c.to_bool() => if_true.flatten()?,
+                    Value::Constant(_) => if_false.flatten()?,
+                    condition => Conditional(Box::new([
+                        condition,
+                        if_true.flatten_lazy(),
+                        if_false.flatten_lazy(),
+                    ])),
                 }
             }
         })
     }
+
+    /// Flatten a value which may never be evaluated, leaving it intact if flattening fails
+    fn flatten_lazy(self) -> Value<T> {
+        self.clone().flatten().unwrap_or(self)
+    }
 }
 
-impl<T: Number, const N: usize> Value<T, N> {
-    fn evaluate(&self, params: &[T; N]) -> Result<T, EvalError> {
+impl<T: Number> Value<T> {
+    fn evaluate(&self, params: &[T]) -> Result<T, EvalError> {
         match self {
             Self::Constant(val) => Ok(*val),
             Self::Variable(ind) => Ok(params[*ind]),
-            Self::BinaryOperation(op, args) => {
-                op(args[0].evaluate(params)?, args[1].evaluate(params)?)
+            Self::BinaryOperation(op, args, span) => {
+                let a = args[0].evaluate(params)?;
+                let b = args[1].evaluate(params)?;
+                (op.func)(a, b).map_err(|e| e.at(*span, op.op.symbol(), &[a, b]))
+            }
+            Self::ShortCircuit(op, args, span) => {
+                let a = args[0].evaluate(params)?;
+                if let Some(result) = op.op.short_circuit(a.to_bool()) {
+                    return Ok(T::from_bool(result));
+                }
+                let b = args[1].evaluate(params)?;
+                (op.func)(a, b).map_err(|e| e.at(*span, op.op.symbol(), &[a, b]))
+            }
+            Self::UnaryOperation(op, arg, span) => {
+                let val = arg.evaluate(params)?;
+                (op.func)(val).map_err(|e| e.at(*span, op.op.symbol(), &[val]))
             }
-            Self::UnaryOperation(op, arg) => op(arg.evaluate(params)?),
             Self::FunctionInvoke(func) => func.invoke(params),
+            Self::Conditional(args) => {
+                if args[0].evaluate(params)?.to_bool() {
+                    args[1].evaluate(params)
+                } else {
+                    args[2].evaluate(params)
+                }
+            }
         }
     }
+
+    /// Call `f` on this value and every value nested within it
+    fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Value<T>)) {
+        f(self);
+        match self {
+            Self::Constant(_) | Self::Variable(_) => {}
+            Self::BinaryOperation(_, args, _) | Self::ShortCircuit(_, args, _) => {
+                args.iter().for_each(|arg| arg.walk(f))
+            }
+            Self::UnaryOperation(_, arg, _) => arg.walk(f),
+            Self::FunctionInvoke(func) => func.args.iter().for_each(|arg| arg.walk(f)),
+            Self::Conditional(args) => args.iter().for_each(|arg| arg.walk(f)),
+        }
+    }
+
+    /// Indices of the variables referenced, in ascending order
+    fn used_variables(&self) -> BTreeSet<usize> {
+        let mut used = BTreeSet::new();
+        self.walk(&mut |value| {
+            if let Self::Variable(index) = value {
+                used.insert(*index);
+            }
+        });
+        used
+    }
+
+    /// Names of the functions called, in alphabetical order
+    fn used_functions(&self) -> BTreeSet<&str> {
+        let mut used = BTreeSet::new();
+        self.walk(&mut |value| {
+            if let Self::FunctionInvoke(invoke) = value {
+                used.insert(&*invoke.func.name);
+            }
+        });
+        used
+    }
 }
@@ -4,7 +4,6 @@ fn main() {
     loop {
         std::io::stdin()
             .lines()
-            .into_iter()
             .map(|line| line.unwrap())
             .for_each(|line| {
                 let expr = Expr::compile(line).unwrap();
@@ -1,14 +1,107 @@
-use std::{fmt::Debug, str::FromStr};
+use std::{borrow::Cow, fmt::Debug, num::IntErrorKind, str::FromStr};
 
 use num::{traits::CheckedRem, CheckedAdd, CheckedDiv, CheckedMul, CheckedSub};
 
 use crate::EvalError;
 
-pub trait NumberOps: Add + Sub + Mul + Div + Rem + Neg + Pow<Self> {}
-impl<T: Add + Sub + Mul + Div + Rem + Neg + Pow<Self>> NumberOps for T {}
+pub trait NumberOps:
+    Add + Sub + Mul + Div + Rem + Neg + Pow<Self> + OverflowOps + FloatOps
+{
+}
+impl<T: Add + Sub + Mul + Div + Rem + Neg + Pow<Self> + OverflowOps + FloatOps> NumberOps for T {}
+
+pub trait NumberParse: FromStr + ParseRadix {}
+impl<T: FromStr + ParseRadix> NumberParse for T {}
+
+pub trait Number:
+    'static + NumberParse + Copy + Default + PartialOrd + NumberOps + Boolean + Debug
+{
+}
+impl<T: 'static + NumberParse + Copy + Default + PartialOrd + NumberOps + Boolean + Debug> Number
+    for T
+{
+}
+
+/// Why a number literal could not be parsed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiteralError {
+    /// The literal is malformed, or its form is not supported by the number type
+    Invalid,
+    /// The literal is well-formed but its value does not fit in the number type
+    OutOfRange,
+}
+
+/// Parsing of integer literals written in a radix other than 10, such as `0xFF`. The digits are
+/// preceded by `-` for a literal which is read along with its sign, such as `-0x80` for `i8`.
+pub trait ParseRadix: Sized {
+    fn parse_radix(digits: &str, radix: u32) -> Result<Self, LiteralError>;
+}
+
+macro_rules! impl_parse_radix {
+    ($($type:ty),*) => {
+        $(
+            impl ParseRadix for $type {
+                fn parse_radix(digits: &str, radix: u32) -> Result<Self, LiteralError> {
+                    <$type>::from_str_radix(digits, radix).map_err(|e| match e.kind() {
+                        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                            LiteralError::OutOfRange
+                        }
+                        _ => LiteralError::Invalid,
+                    })
+                }
+            }
+        )*
+    };
+    ($($type:ty),*; float) => {
+        $(
+            impl ParseRadix for $type {
+                fn parse_radix(_: &str, _: u32) -> Result<Self, LiteralError> {
+                    Err(LiteralError::Invalid)
+                }
+            }
+        )*
+    };
+}
+
+impl_parse_radix!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+impl_parse_radix!(f32, f64; float);
 
-pub trait Number: 'static + FromStr + Copy + Default + NumberOps + Debug {}
-impl<T: 'static + FromStr + Copy + Default + NumberOps + Debug> Number for T {}
+/// Parse a number literal as lexed from source, handling radix prefixes and `_` separators, and
+/// negating it if it is read along with a preceding `-`
+pub(crate) fn parse_literal<T: Number>(text: &str, negative: bool) -> Result<T, LiteralError> {
+    let (radix, digits) = match text.get(..2) {
+        Some("0x" | "0X") => (16, &text[2..]),
+        Some("0b" | "0B") => (2, &text[2..]),
+        Some("0o" | "0O") => (8, &text[2..]),
+        _ => (10, text),
+    };
+    // Most literals are parsed as written, so only copy those with a sign or separators
+    let digits = if negative || digits.contains('_') {
+        let sign = negative.then_some('-');
+        Cow::Owned(
+            sign.into_iter()
+                .chain(digits.chars().filter(|&c| c != '_'))
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(digits)
+    };
+    let magnitude = digits.strip_prefix('-').unwrap_or(&digits);
+    if radix == 10 {
+        return digits.parse().map_err(|_| {
+            // Plain digits can only fail to parse by being too large for the type
+            if magnitude.chars().all(|c| c.is_ascii_digit()) {
+                LiteralError::OutOfRange
+            } else {
+                LiteralError::Invalid
+            }
+        });
+    }
+    if magnitude.is_empty() || !magnitude.chars().all(|c| c.is_digit(radix)) {
+        return Err(LiteralError::Invalid);
+    }
+    T::parse_radix(&digits, radix)
+}
 
 macro_rules! op_trait {
     ($name:ident, $op_name:ident) => {
@@ -69,6 +162,49 @@ macro_rules! impl_op_traits {
         impl_op_trait!($type, Mul, mul, checked_mul);
         impl_op_trait!($type, Div, div, checked_div);
         impl_op_trait!($type, Rem, rem, checked_rem);
+        impl_bitwise!($type);
+    };
+}
+
+/// Bitwise operations on integer types
+pub trait Bitwise: Sized {
+    fn bit_and(&self, other: Self) -> Self;
+    fn bit_or(&self, other: Self) -> Self;
+    fn bit_xor(&self, other: Self) -> Self;
+    fn bit_not(&self) -> Self;
+    /// Shift left, or `None` if the shift amount is negative or not less than the bit width
+    fn shl(&self, other: Self) -> Option<Self>;
+    /// Shift right, or `None` if the shift amount is negative or not less than the bit width
+    fn shr(&self, other: Self) -> Option<Self>;
+}
+
+macro_rules! impl_bitwise {
+    ($type:ty) => {
+        impl Bitwise for $type {
+            fn bit_and(&self, other: Self) -> Self {
+                self & other
+            }
+
+            fn bit_or(&self, other: Self) -> Self {
+                self | other
+            }
+
+            fn bit_xor(&self, other: Self) -> Self {
+                self ^ other
+            }
+
+            fn bit_not(&self) -> Self {
+                !self
+            }
+
+            fn shl(&self, other: Self) -> Option<Self> {
+                self.checked_shl(u32::try_from(other).ok()?)
+            }
+
+            fn shr(&self, other: Self) -> Option<Self> {
+                self.checked_shr(u32::try_from(other).ok()?)
+            }
+        }
     };
 }
 
@@ -82,6 +218,8 @@ impl_op_traits!(u16);
 impl_op_traits!(u32);
 impl_op_traits!(u64);
 impl_op_traits!(u128);
+impl_op_traits!(isize);
+impl_op_traits!(usize);
 impl_op_traits_f!(f32);
 impl_op_traits_f!(f64);
 
@@ -97,7 +235,16 @@ macro_rules! impl_pow {
                 if exp < 0 {
                     return Err(EvalError::NegativeIntegerExponent);
                 }
-                Ok(<$lhs>::pow(*self, exp as $rhs))
+                match <$rhs>::try_from(exp) {
+                    Ok(exp) => <$lhs>::checked_pow(*self, exp).ok_or(EvalError::Overflow),
+                    // Only 0, 1 and -1 can be raised to a power this large without overflowing.
+                    // -1 is the only value which overflows when incremented to 0.
+                    Err(_) if *self == 0 || *self == 1 => Ok(*self),
+                    Err(_) if <$lhs>::checked_add(*self, 1) == Some(0) => {
+                        Ok(if exp % 2 == 0 { 1 } else { *self })
+                    }
+                    Err(_) => Err(EvalError::Overflow),
+                }
             }
         }
     };
@@ -119,19 +266,310 @@ impl_pow!(u16, u32);
 impl_pow!(i64, u32);
 impl_pow!(u64, u32);
 impl_pow!(i128, u32);
+impl_pow!(u128, u32);
+impl_pow!(isize, u32);
+impl_pow!(usize, u32);
 impl_pow!(f32, f32, f);
 impl_pow!(f64, f64, f);
 
-pub trait Neg {
-    fn neg(&self) -> Self;
+pub trait Neg: Sized {
+    /// Negate the number, or `None` if the result is not representable, as for any nonzero
+    /// unsigned integer or the minimum value of a signed integer
+    fn neg(&self) -> Option<Self>;
 }
 
 impl<T: Sub + Default + Copy> Neg for T {
-    fn neg(&self) -> Self {
-        Self::default().sub(*self).unwrap()
+    fn neg(&self) -> Option<Self> {
+        Self::default().sub(*self)
     }
 }
 
+/// How integer operators behave when the result does not fit in the number type
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegerMode {
+    /// Return [`EvalError::Overflow`]
+    #[default]
+    Checked,
+    /// Wrap around at the bounds of the type, as in two's complement arithmetic
+    Wrapping,
+    /// Clamp to the minimum or maximum value of the type. A negative literal which does not fit,
+    /// such as `-5` for `u8`, is still rejected when compiling.
+    Saturating,
+}
+
+/// How floating-point operators handle results which are not finite numbers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FloatMode {
+    /// Division by zero returns [`EvalError::DivideByZero`], while other operations may quietly
+    /// produce NaN or an infinity
+    #[default]
+    Standard,
+    /// Any operator or function producing NaN or an infinity returns [`EvalError::NonFinite`], as
+    /// does evaluating with a variable which is not finite. Constants which are not finite, such
+    /// as `inf` from [`ExprEnv::with_math_constants`](crate::env::ExprEnv::with_math_constants),
+    /// are rejected when compiling.
+    Strict,
+    /// Follow IEEE 754, so dividing by zero gives an infinity or NaN
+    Ieee,
+}
+
+/// Floating-point behaviour selected by [`FloatMode`]. Integers are always finite and keep their
+/// usual division, so they are unaffected by it.
+pub trait FloatOps: Sized {
+    const FLOAT: bool;
+    fn is_finite(&self) -> bool;
+    /// Division following IEEE 754 for floats. Integers return `None` when dividing by zero.
+    fn ieee_div(&self, other: Self) -> Option<Self>;
+    fn ieee_rem(&self, other: Self) -> Option<Self>;
+}
+
+macro_rules! impl_float_ops {
+    ($($type:ty),*) => {
+        $(
+            impl FloatOps for $type {
+                const FLOAT: bool = false;
+
+                fn is_finite(&self) -> bool {
+                    true
+                }
+
+                fn ieee_div(&self, other: Self) -> Option<Self> {
+                    Div::div(self, other)
+                }
+
+                fn ieee_rem(&self, other: Self) -> Option<Self> {
+                    Rem::rem(self, other)
+                }
+            }
+        )*
+    };
+    ($($type:ty),*; float) => {
+        $(
+            impl FloatOps for $type {
+                const FLOAT: bool = true;
+
+                fn is_finite(&self) -> bool {
+                    <$type>::is_finite(*self)
+                }
+
+                fn ieee_div(&self, other: Self) -> Option<Self> {
+                    Some(self / other)
+                }
+
+                fn ieee_rem(&self, other: Self) -> Option<Self> {
+                    Some(self % other)
+                }
+            }
+        )*
+    };
+}
+
+impl_float_ops!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+impl_float_ops!(f32, f64; float);
+
+/// Pass a finite result through, or return [`EvalError::NonFinite`] for NaN or an infinity
+pub(crate) fn finite<T: Number>(val: T) -> Result<T, EvalError> {
+    if val.is_finite() {
+        Ok(val)
+    } else {
+        Err(EvalError::NonFinite)
+    }
+}
+
+/// Operations which wrap or saturate on overflow instead of failing. Floats do not overflow, so
+/// for them these are the ordinary operations. Division and remainder return `None` when
+/// dividing by zero.
+pub trait OverflowOps: Sized {
+    fn wrapping_add(&self, other: Self) -> Self;
+    fn wrapping_sub(&self, other: Self) -> Self;
+    fn wrapping_mul(&self, other: Self) -> Self;
+    fn wrapping_div(&self, other: Self) -> Option<Self>;
+    fn wrapping_rem(&self, other: Self) -> Option<Self>;
+    fn wrapping_neg(&self) -> Self;
+    fn wrapping_pow(&self, exp: Self) -> Result<Self, EvalError>;
+    fn saturating_add(&self, other: Self) -> Self;
+    fn saturating_sub(&self, other: Self) -> Self;
+    fn saturating_mul(&self, other: Self) -> Self;
+    fn saturating_div(&self, other: Self) -> Option<Self>;
+    fn saturating_rem(&self, other: Self) -> Option<Self>;
+    fn saturating_neg(&self) -> Self;
+    fn saturating_pow(&self, exp: Self) -> Result<Self, EvalError>;
+}
+
+macro_rules! impl_overflow_ops {
+    ($($type:ty),*) => {
+        $(
+            #[allow(unused_comparisons)]
+            impl OverflowOps for $type {
+                fn wrapping_add(&self, other: Self) -> Self {
+                    <$type>::wrapping_add(*self, other)
+                }
+
+                fn wrapping_sub(&self, other: Self) -> Self {
+                    <$type>::wrapping_sub(*self, other)
+                }
+
+                fn wrapping_mul(&self, other: Self) -> Self {
+                    <$type>::wrapping_mul(*self, other)
+                }
+
+                fn wrapping_div(&self, other: Self) -> Option<Self> {
+                    (other != 0).then(|| <$type>::wrapping_div(*self, other))
+                }
+
+                fn wrapping_rem(&self, other: Self) -> Option<Self> {
+                    (other != 0).then(|| <$type>::wrapping_rem(*self, other))
+                }
+
+                fn wrapping_neg(&self) -> Self {
+                    <$type>::wrapping_neg(*self)
+                }
+
+                fn wrapping_pow(&self, exp: Self) -> Result<Self, EvalError> {
+                    if exp < 0 {
+                        return Err(EvalError::NegativeIntegerExponent);
+                    }
+                    // Square and multiply over the whole exponent, which may not fit in a u32
+                    let (mut base, mut exp, mut result): (Self, Self, Self) = (*self, exp, 1);
+                    while exp > 0 {
+                        if exp & 1 == 1 {
+                            result = result.wrapping_mul(base);
+                        }
+                        base = base.wrapping_mul(base);
+                        exp >>= 1;
+                    }
+                    Ok(result)
+                }
+
+                fn saturating_add(&self, other: Self) -> Self {
+                    <$type>::saturating_add(*self, other)
+                }
+
+                fn saturating_sub(&self, other: Self) -> Self {
+                    <$type>::saturating_sub(*self, other)
+                }
+
+                fn saturating_mul(&self, other: Self) -> Self {
+                    <$type>::saturating_mul(*self, other)
+                }
+
+                fn saturating_div(&self, other: Self) -> Option<Self> {
+                    (other != 0).then(|| <$type>::saturating_div(*self, other))
+                }
+
+                fn saturating_rem(&self, other: Self) -> Option<Self> {
+                    // The remainder only overflows for `MIN % -1`, where wrapping gives the exact 0
+                    (other != 0).then(|| <$type>::wrapping_rem(*self, other))
+                }
+
+                fn saturating_neg(&self) -> Self {
+                    <$type>::saturating_sub(0, *self)
+                }
+
+                fn saturating_pow(&self, exp: Self) -> Result<Self, EvalError> {
+                    match Pow::pow(self, exp) {
+                        Err(EvalError::Overflow) if *self < 0 && exp % 2 == 1 => Ok(<$type>::MIN),
+                        Err(EvalError::Overflow) => Ok(<$type>::MAX),
+                        result => result,
+                    }
+                }
+            }
+        )*
+    };
+    ($($type:ty),*; float) => {
+        $(
+            impl OverflowOps for $type {
+                fn wrapping_add(&self, other: Self) -> Self {
+                    self + other
+                }
+
+                fn wrapping_sub(&self, other: Self) -> Self {
+                    self - other
+                }
+
+                fn wrapping_mul(&self, other: Self) -> Self {
+                    self * other
+                }
+
+                fn wrapping_div(&self, other: Self) -> Option<Self> {
+                    Div::div(self, other)
+                }
+
+                fn wrapping_rem(&self, other: Self) -> Option<Self> {
+                    Rem::rem(self, other)
+                }
+
+                fn wrapping_neg(&self) -> Self {
+                    -self
+                }
+
+                fn wrapping_pow(&self, exp: Self) -> Result<Self, EvalError> {
+                    Pow::pow(self, exp)
+                }
+
+                fn saturating_add(&self, other: Self) -> Self {
+                    self + other
+                }
+
+                fn saturating_sub(&self, other: Self) -> Self {
+                    self - other
+                }
+
+                fn saturating_mul(&self, other: Self) -> Self {
+                    self * other
+                }
+
+                fn saturating_div(&self, other: Self) -> Option<Self> {
+                    Div::div(self, other)
+                }
+
+                fn saturating_rem(&self, other: Self) -> Option<Self> {
+                    Rem::rem(self, other)
+                }
+
+                fn saturating_neg(&self) -> Self {
+                    -self
+                }
+
+                fn saturating_pow(&self, exp: Self) -> Result<Self, EvalError> {
+                    Pow::pow(self, exp)
+                }
+            }
+        )*
+    };
+}
+
+impl_overflow_ops!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+impl_overflow_ops!(f32, f64; float);
+
+/// Conversion between numbers and truth values, used by comparison and logical operators
+pub trait Boolean {
+    fn from_bool(b: bool) -> Self;
+    fn to_bool(&self) -> bool;
+}
+
+macro_rules! impl_boolean {
+    ($($type:ty),*) => {
+        $(
+            impl Boolean for $type {
+                fn from_bool(b: bool) -> Self {
+                    if b {
+                        1 as $type
+                    } else {
+                        0 as $type
+                    }
+                }
+
+                fn to_bool(&self) -> bool {
+                    *self != 0 as $type
+                }
+            }
+        )*
+    };
+}
+
+impl_boolean!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64);
+
 pub trait Trig {
     fn sin(&self) -> Self;
     fn cos(&self) -> Self;
@@ -158,3 +596,211 @@ macro_rules! impl_trig {
 
 impl_trig!(f32, f32);
 impl_trig!(f64, f64);
+
+macro_rules! float_func {
+    ($name:ident) => {
+        fn $name(&self) -> Self {
+            <Self>::$name(*self)
+        }
+    };
+    ($name:ident, $arg:ident) => {
+        fn $name(&self, $arg: Self) -> Self {
+            <Self>::$name(*self, $arg)
+        }
+    };
+}
+
+pub trait InverseTrig {
+    fn asin(&self) -> Self;
+    fn acos(&self) -> Self;
+    fn atan(&self) -> Self;
+    fn atan2(&self, x: Self) -> Self;
+}
+
+pub trait Hyperbolic {
+    fn sinh(&self) -> Self;
+    fn cosh(&self) -> Self;
+    fn tanh(&self) -> Self;
+    fn asinh(&self) -> Self;
+    fn acosh(&self) -> Self;
+    fn atanh(&self) -> Self;
+}
+
+pub trait Roots {
+    fn sqrt(&self) -> Self;
+    fn cbrt(&self) -> Self;
+    fn hypot(&self, other: Self) -> Self;
+}
+
+pub trait Logarithm {
+    fn exp(&self) -> Self;
+    fn ln(&self) -> Self;
+    fn log10(&self) -> Self;
+    fn log2(&self) -> Self;
+    fn log(&self, base: Self) -> Self;
+}
+
+macro_rules! impl_float_math {
+    ($target:ty) => {
+        impl InverseTrig for $target {
+            float_func!(asin);
+            float_func!(acos);
+            float_func!(atan);
+            float_func!(atan2, x);
+        }
+
+        impl Hyperbolic for $target {
+            float_func!(sinh);
+            float_func!(cosh);
+            float_func!(tanh);
+            float_func!(asinh);
+            float_func!(acosh);
+            float_func!(atanh);
+        }
+
+        impl Roots for $target {
+            float_func!(sqrt);
+            float_func!(cbrt);
+            float_func!(hypot, other);
+        }
+
+        impl Logarithm for $target {
+            float_func!(exp);
+            float_func!(ln);
+            float_func!(log10);
+            float_func!(log2);
+            float_func!(log, base);
+        }
+
+        impl Rounding for $target {
+            float_func!(floor);
+            float_func!(ceil);
+            float_func!(round);
+            float_func!(trunc);
+        }
+
+        impl Sign for $target {
+            fn abs(&self) -> Option<Self> {
+                Some(<$target>::abs(*self))
+            }
+
+            fn sign(&self) -> Self {
+                if *self > 0.0 {
+                    1.0
+                } else if *self < 0.0 {
+                    -1.0
+                } else {
+                    // Zero or NaN
+                    *self
+                }
+            }
+        }
+    };
+}
+
+impl_float_math!(f32);
+impl_float_math!(f64);
+
+/// Rounding to an integer. Integer types round to themselves.
+pub trait Rounding {
+    fn floor(&self) -> Self;
+    fn ceil(&self) -> Self;
+    fn round(&self) -> Self;
+    fn trunc(&self) -> Self;
+}
+
+macro_rules! impl_rounding_int {
+    ($($type:ty),*) => {
+        $(
+            impl Rounding for $type {
+                fn floor(&self) -> Self {
+                    *self
+                }
+
+                fn ceil(&self) -> Self {
+                    *self
+                }
+
+                fn round(&self) -> Self {
+                    *self
+                }
+
+                fn trunc(&self) -> Self {
+                    *self
+                }
+            }
+        )*
+    };
+}
+
+impl_rounding_int!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+pub trait Sign: Sized {
+    /// The absolute value, or `None` if it cannot be represented
+    fn abs(&self) -> Option<Self>;
+    /// -1, 0 or 1 depending on the sign of the value
+    fn sign(&self) -> Self;
+}
+
+macro_rules! impl_sign_int {
+    ($($type:ty),*) => {
+        $(
+            impl Sign for $type {
+                fn abs(&self) -> Option<Self> {
+                    self.checked_abs()
+                }
+
+                fn sign(&self) -> Self {
+                    self.signum()
+                }
+            }
+        )*
+    };
+    ($($type:ty),*; unsigned) => {
+        $(
+            impl Sign for $type {
+                fn abs(&self) -> Option<Self> {
+                    Some(*self)
+                }
+
+                fn sign(&self) -> Self {
+                    Self::from(*self != 0)
+                }
+            }
+        )*
+    };
+}
+
+impl_sign_int!(i8, i16, i32, i64, i128, isize);
+impl_sign_int!(u8, u16, u32, u64, u128, usize; unsigned);
+
+/// Every math trait used by [`ExprEnv::with_std_math`](crate::env::ExprEnv::with_std_math)
+pub trait StdMath: Trig + InverseTrig + Hyperbolic + Roots + Logarithm + Rounding + Sign {}
+impl<T: Trig + InverseTrig + Hyperbolic + Roots + Logarithm + Rounding + Sign> StdMath for T {}
+
+/// Well-known constants of floating point types
+pub trait MathConsts {
+    const PI: Self;
+    const E: Self;
+    const TAU: Self;
+    /// The golden ratio
+    const PHI: Self;
+    const INFINITY: Self;
+    const NAN: Self;
+}
+
+macro_rules! impl_math_consts {
+    ($target:ident) => {
+        impl MathConsts for $target {
+            const PI: Self = std::$target::consts::PI;
+            const E: Self = std::$target::consts::E;
+            const TAU: Self = std::$target::consts::TAU;
+            const PHI: Self = 1.618_033_988_749_895_f64 as $target;
+            const INFINITY: Self = $target::INFINITY;
+            const NAN: Self = $target::NAN;
+        }
+    };
+}
+
+impl_math_consts!(f32);
+impl_math_consts!(f64);
@@ -1,56 +1,251 @@
+use std::fmt::{self, Display};
+
+use crate::func::Arity;
+pub use crate::lexer::Span;
+use crate::lexer::{Lexeme, LexemeKind};
+
 pub(crate) struct ParserState<'a> {
-    pub source: &'a [char],
+    pub source: &'a [Lexeme<'a>],
     pub pos: usize,
+    /// Length of the source string, used as the error position at the end of input
+    pub len: usize,
 }
 
-#[derive(Debug)]
+/// An error encountered while compiling an expression. Spans are byte offsets into the
+/// original source string.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParserError {
-    ExpectedChar(usize, char),
-    ExpectedStr(usize, &'static str),
-    ExpectedToken(usize, &'static str),
-    MissingOperand(usize),
-    DanglingValue,
-    NoValue,
+    /// Expected the given character, or the end of input if it is `'\0'`
+    ExpectedChar(Span, char),
+    ExpectedToken(Span, &'static str),
+    /// A name not defined in the environment, with the closest defined name if there is one
+    UnknownName(Span, String, Option<String>),
+    /// A function called with the wrong number of arguments, with the number it was given
+    ArgumentCount(Span, String, Arity, usize),
+    /// A malformed number literal, or one whose form the number type does not support
+    InvalidLiteral(Span, String, &'static str),
+    /// A number literal too large for the number type, including a float literal which is only
+    /// representable as an infinity under [`FloatMode::Strict`](crate::number::FloatMode::Strict)
+    LiteralOutOfRange(Span, String, &'static str),
+    /// A named constant which is NaN or infinite, which
+    /// [`FloatMode::Strict`](crate::number::FloatMode::Strict) does not allow
+    NonFiniteConstant(Span, String),
+    /// The source is empty or only whitespace
+    EmptyInput(Span),
+    /// A value left over once the operators have taken their operands
+    DanglingValue(Span),
+    /// An operator without an operand
+    NoValue(Span),
 }
 
-impl ParserState<'_> {
-    pub fn peek(&self) -> Option<char> {
-        self.source.get(self.pos).copied()
+impl ParserError {
+    /// The part of the source the error refers to
+    pub fn span(&self) -> Option<Span> {
+        use ParserError::*;
+        match self {
+            ExpectedChar(span, _)
+            | ExpectedToken(span, _)
+            | UnknownName(span, _, _)
+            | ArgumentCount(span, _, _, _)
+            | InvalidLiteral(span, _, _)
+            | LiteralOutOfRange(span, _, _)
+            | NonFiniteConstant(span, _)
+            | EmptyInput(span)
+            | DanglingValue(span)
+            | NoValue(span) => Some(*span),
+        }
+    }
+
+    /// The 1-based line and column at which the error occurred in the given source
+    pub fn line_col(&self, source: &str) -> Option<(usize, usize)> {
+        let span = self.span()?;
+        let (line, line_start) = line_of(source, span.start);
+        Some((line + 1, source[line_start..span.start].chars().count() + 1))
     }
 
-    pub fn advance(&mut self) -> Option<char> {
-        let c = self.source.get(self.pos).copied();
+    /// Render the error with the offending source line and a caret under the offending token
+    ///
+    /// Example:
+    /// ```
+    /// use crunch_eval::{env::ExprEnv, expr::Expr};
+    ///
+    /// let source = "sinn(x)";
+    /// let env = ExprEnv::<f64, 1>::new(["x"]).with_trig();
+    /// let err = Expr::compile_env(source, env).unwrap_err();
+    /// assert_eq!(
+    ///     err.render(source),
+    ///     "error: unknown name `sinn`, did you mean `sin`?\n --> 1:1\n  |\n1 | sinn(x)\n  | ^^^^\n"
+    /// );
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let mut out = format!("error: {}\n", self.message());
+        let Some(span) = self.span() else {
+            return out;
+        };
+        let (line, line_start) = line_of(source, span.start);
+        let line_end = source[line_start..]
+            .find('\n')
+            .map_or(source.len(), |i| line_start + i);
+        let text = &source[line_start..line_end];
+        let col = source[line_start..span.start].chars().count();
+        let number = (line + 1).to_string();
+        let gutter = " ".repeat(number.len());
+        // Keep tabs so that the caret lines up with the source text
+        let padding: String = text
+            .chars()
+            .take(col)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source[span.start..span.end.clamp(span.start, line_end)]
+            .chars()
+            .count()
+            .max(1);
+        out += &format!("{gutter}--> {}:{}\n", line + 1, col + 1);
+        out += &format!("{gutter} |\n");
+        out += &format!("{number} | {text}\n");
+        out += &format!("{gutter} | {padding}{}\n", "^".repeat(width));
+        out
+    }
+
+    fn message(&self) -> String {
+        use ParserError::*;
+        match self {
+            ExpectedChar(_, '\0') => "expected end of input".into(),
+            ExpectedChar(_, c) => format!("expected `{c}`"),
+            ExpectedToken(_, token) => format!("expected {token}"),
+            UnknownName(_, name, Some(suggestion)) => {
+                format!("unknown name `{name}`, did you mean `{suggestion}`?")
+            }
+            UnknownName(_, name, None) => format!("unknown name `{name}`"),
+            ArgumentCount(_, name, arity, given) => {
+                let were = if *given == 1 { "was" } else { "were" };
+                format!("`{name}` takes {arity} but {given} {were} given")
+            }
+            InvalidLiteral(_, literal, type_name) => {
+                format!("invalid literal `{literal}` for type `{type_name}`")
+            }
+            LiteralOutOfRange(_, literal, type_name) => {
+                format!("literal `{literal}` does not fit in type `{type_name}`")
+            }
+            NonFiniteConstant(_, name) => {
+                format!("constant `{name}` is not finite, as strict float mode requires")
+            }
+            EmptyInput(_) => "empty expression".into(),
+            DanglingValue(_) => "unexpected value".into(),
+            NoValue(_) => "expected a value".into(),
+        }
+    }
+}
+
+impl Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message())?;
+        if let Some(span) = self.span() {
+            write!(f, " at offset {}", span.start)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParserError {}
+
+/// The 0-based line containing the byte offset, and the offset at which that line starts
+fn line_of(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    (
+        before.matches('\n').count(),
+        before.rfind('\n').map_or(0, |i| i + 1),
+    )
+}
+
+/// Find the candidate most similar to an unknown name, if any is close enough to be a likely typo
+pub(crate) fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .min()
+        .map(|(_, c)| c.to_owned())
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+impl<'a> ParserState<'a> {
+    pub fn peek(&self) -> Option<&Lexeme<'a>> {
+        self.source.get(self.pos)
+    }
+
+    /// The next lexeme if it is a single-character symbol
+    pub fn peek_char(&self) -> Option<char> {
+        self.peek()
+            .filter(|l| l.kind == LexemeKind::Symbol)
+            .and_then(|l| {
+                let mut chars = l.text.chars();
+                chars.next().filter(|_| chars.next().is_none())
+            })
+    }
+
+    pub fn advance(&mut self) -> Option<Lexeme<'a>> {
+        let lexeme = self.source.get(self.pos).copied();
         self.pos += 1;
-        c
+        lexeme
+    }
+
+    /// Span of the next lexeme, or an empty span at the end of input
+    pub fn span(&self) -> Span {
+        self.peek().map_or(
+            Span {
+                start: self.len,
+                end: self.len,
+            },
+            |l| l.span,
+        )
+    }
+
+    /// Span of the most recently consumed lexeme
+    pub fn prev_span(&self) -> Span {
+        self.source[self.pos - 1].span
     }
 
     pub fn assert_char(&mut self, c: char) -> Result<(), ParserError> {
-        if self.advance() == Some(c) {
+        if self.check_char(c) {
             Ok(())
         } else {
-            Err(ParserError::ExpectedChar(self.pos, c))
+            Err(ParserError::ExpectedChar(self.span(), c))
         }
     }
 
-    pub fn take_while(
+    pub fn take(
         &mut self,
         token_type: &'static str,
-        filter: fn(char) -> bool,
-    ) -> Result<String, ParserError> {
-        let collected = self.source[self.pos..]
-            .iter()
-            .take_while(|&c| filter(*c))
-            .collect::<String>();
-        if collected.len() > 0 {
-            self.pos += collected.len();
-            Ok(collected)
-        } else {
-            Err(ParserError::ExpectedToken(self.pos, token_type))
+        kind: LexemeKind,
+    ) -> Result<&'a str, ParserError> {
+        match self.peek() {
+            Some(lexeme) if lexeme.kind == kind => {
+                let text = lexeme.text;
+                self.pos += 1;
+                Ok(text)
+            }
+            _ => Err(ParserError::ExpectedToken(self.span(), token_type)),
         }
     }
 
     pub fn check_char(&mut self, c: char) -> bool {
-        if self.peek() == Some(c) {
+        if self.peek_char() == Some(c) {
             self.pos += 1;
             true
         } else {
@@ -0,0 +1,234 @@
+use crate::{
+    ast::{BinaryOp, UnaryOp},
+    compiler::Modes,
+    diff::{is_one, is_zero},
+    func::FunctionInvoke,
+    number::IntegerMode,
+    parser::Span,
+    BinaryFn, Number, Operator, UnaryFn, Value,
+};
+
+/// Which rewrites [`Expr::simplify`](crate::expr::Expr::simplify) may make
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Only make rewrites which give the same result or error for every input, including NaN,
+    /// infinities, the sign of zero and integer overflow. For example, `x + 0` is left intact
+    /// for floats because `-0.0 + 0.0` is `0.0`, and `0 * x` because `0.0 * NaN` is NaN.
+    #[default]
+    Exact,
+    /// Also make rewrites which hold for finite numbers without overflow, such as `0 * x` to `0`
+    /// and `(x + 2) + 3` to `x + 5` for floats. This may change results for NaN, infinities and
+    /// signed zeros, change the rounding of floats, and drop errors from removed subexpressions.
+    Relaxed,
+}
+
+/// Simplify a value which has already been flattened, under the modes it was compiled with
+pub(crate) fn simplify<T: Number>(
+    value: Value<T>,
+    modes: Modes,
+    strictness: Strictness,
+) -> Value<T> {
+    Simplifier { modes, strictness }.simplify(value)
+}
+
+struct Simplifier {
+    modes: Modes,
+    strictness: Strictness,
+}
+
+impl Simplifier {
+    fn relaxed(&self) -> bool {
+        self.strictness == Strictness::Relaxed
+    }
+
+    fn simplify<T: Number>(&self, value: Value<T>) -> Value<T> {
+        match value {
+            Value::Constant(_) | Value::Variable(_) => value,
+            Value::BinaryOperation(op, args, span) | Value::ShortCircuit(op, args, span) => {
+                let [left, right] = (*args).map(|arg| self.simplify(arg));
+                self.binary(op, left, right, span)
+            }
+            Value::UnaryOperation(op, arg, span) => {
+                let arg = self.simplify(*arg);
+                self.unary(op, arg, span)
+            }
+            Value::FunctionInvoke(invoke) => Value::FunctionInvoke(Box::new(FunctionInvoke::new(
+                invoke.func,
+                invoke
+                    .args
+                    .into_iter()
+                    .map(|arg| self.simplify(arg))
+                    .collect(),
+                invoke.span,
+            ))),
+            Value::Conditional(args) => {
+                let [condition, if_true, if_false] = (*args).map(|arg| self.simplify(arg));
+                match condition {
+                    Value::Constant(c) if c.to_bool() => if_true,
+                    Value::Constant(_) => if_false,
+                    condition => Value::Conditional(Box::new([condition, if_true, if_false])),
+                }
+            }
+        }
+    }
+
+    fn binary<T: Number>(
+        &self,
+        op: Operator<BinaryOp, BinaryFn<T>>,
+        left: Value<T>,
+        right: Value<T>,
+        span: Span,
+    ) -> Value<T> {
+        use BinaryOp::*;
+        // Rewrites can bring constants together, which are folded unless that would fail
+        if let (Value::Constant(a), Value::Constant(b)) = (&left, &right) {
+            if let Ok(val) = (op.func)(*a, *b) {
+                return Value::Constant(val);
+            }
+        }
+        match op.op {
+            Add if is_zero(&right) && self.adds_zero::<T>() => return left,
+            Add if is_zero(&left) && self.adds_zero::<T>() => return right,
+            Sub if is_zero(&right) => return left,
+            Mul if is_one(&right) => return left,
+            Mul if is_one(&left) => return right,
+            Div | Pow if is_one(&right) => return left,
+            Mul if (is_zero(&left) && self.annihilates(&right))
+                || (is_zero(&right) && self.annihilates(&left)) =>
+            {
+                return Value::Constant(T::default());
+            }
+            // Even NaN to the power of 0 is 1
+            Pow if is_zero(&right) && self.drops(&left) => {
+                return Value::Constant(T::from_bool(true));
+            }
+            Sub if same(&left, &right) && self.annihilates(&left) => {
+                return Value::Constant(T::default());
+            }
+            Add | Mul => {
+                if let Some(value) = self.reassociate(op, &left, &right, span) {
+                    return value;
+                }
+            }
+            _ => {}
+        }
+        Value::binary(op, Box::new([left, right]), span)
+    }
+
+    fn unary<T: Number>(
+        &self,
+        op: Operator<UnaryOp, UnaryFn<T>>,
+        arg: Value<T>,
+        span: Span,
+    ) -> Value<T> {
+        if let Value::Constant(val) = arg {
+            if let Ok(val) = (op.func)(val) {
+                return Value::Constant(val);
+            }
+        }
+        match arg {
+            Value::UnaryOperation(inner, arg, _)
+                if op.op == UnaryOp::Neg
+                    && inner.op == UnaryOp::Neg
+                    && self.removes_double_negation::<T>() =>
+            {
+                *arg
+            }
+            arg => Value::UnaryOperation(op, Box::new(arg), span),
+        }
+    }
+
+    /// Turn `(x op a) op b` into `x op (a op b)` for a commutative operator and constants `a`
+    /// and `b`, in any order of operands
+    fn reassociate<T: Number>(
+        &self,
+        op: Operator<BinaryOp, BinaryFn<T>>,
+        left: &Value<T>,
+        right: &Value<T>,
+        span: Span,
+    ) -> Option<Value<T>> {
+        let (inner, outer) = match (left, right) {
+            (inner, Value::Constant(c)) | (Value::Constant(c), inner) => (inner, *c),
+            _ => return None,
+        };
+        let Value::BinaryOperation(inner_op, args, _) = inner else {
+            return None;
+        };
+        let (x, c) = match &**args {
+            [x, Value::Constant(c)] | [Value::Constant(c), x] => (x, *c),
+            _ => return None,
+        };
+        if inner_op.op != op.op || !self.reassociates(op.op, c, outer) {
+            return None;
+        }
+        let folded = (op.func)(c, outer).ok()?;
+        Some(self.binary(op, x.clone(), Value::Constant(folded), span))
+    }
+
+    /// Whether `x + 0` can be `x`, which isn't so for floats as `-0.0 + 0.0` is `0.0`
+    fn adds_zero<T: Number>(&self) -> bool {
+        self.relaxed() || !T::FLOAT
+    }
+
+    /// Whether `x` can be removed, without losing an error it could have raised
+    fn drops<T: Number>(&self, x: &Value<T>) -> bool {
+        self.relaxed() || matches!(x, Value::Constant(_) | Value::Variable(_))
+    }
+
+    /// Whether `x` can be removed from `0 * x` or `x - x` to leave 0, which isn't so for floats
+    /// as it may be NaN or infinite
+    fn annihilates<T: Number>(&self, x: &Value<T>) -> bool {
+        (self.relaxed() || !T::FLOAT) && self.drops(x)
+    }
+
+    /// Whether `--x` can be `x`. For integers, unless they wrap, negating the minimum value
+    /// fails or saturates.
+    fn removes_double_negation<T: Number>(&self) -> bool {
+        self.relaxed() || T::FLOAT || self.modes.integer == IntegerMode::Wrapping
+    }
+
+    /// Whether `(x op a) op b` gives the same result as `x op (a op b)`
+    fn reassociates<T: Number>(&self, op: BinaryOp, a: T, b: T) -> bool {
+        let zero = T::default();
+        if self.relaxed() || (self.modes.integer == IntegerMode::Wrapping && !T::FLOAT) {
+            return true;
+        }
+        // A saturated `a op b` would be folded without an error, but no longer be the amount
+        // `x` is moved by
+        let fits = match op {
+            BinaryOp::Add => a.add(b).is_some(),
+            BinaryOp::Mul => a.mul(b).is_some(),
+            _ => false,
+        };
+        match op {
+            // Floats round at each step
+            _ if T::FLOAT => false,
+            _ if !fits => false,
+            // Overflow can only be avoided by the first step if the second goes back the other way
+            BinaryOp::Add => (a >= zero && b >= zero) || (a <= zero && b <= zero),
+            // Multiplying by a positive number keeps an overflowed product out of range
+            BinaryOp::Mul => b > zero,
+            _ => false,
+        }
+    }
+}
+
+/// Whether two values are the same operations on the same operands. Function calls never are,
+/// as functions may not be pure.
+fn same<T: Number>(a: &Value<T>, b: &Value<T>) -> bool {
+    match (a, b) {
+        (Value::Constant(a), Value::Constant(b)) => a == b,
+        (Value::Variable(a), Value::Variable(b)) => a == b,
+        (Value::BinaryOperation(op_a, a, _), Value::BinaryOperation(op_b, b, _))
+        | (Value::ShortCircuit(op_a, a, _), Value::ShortCircuit(op_b, b, _)) => {
+            op_a.op == op_b.op && same(&a[0], &b[0]) && same(&a[1], &b[1])
+        }
+        (Value::UnaryOperation(op_a, a, _), Value::UnaryOperation(op_b, b, _)) => {
+            op_a.op == op_b.op && same(a, b)
+        }
+        (Value::Conditional(a), Value::Conditional(b)) => {
+            a.iter().zip(b.iter()).all(|(a, b)| same(a, b))
+        }
+        _ => false,
+    }
+}
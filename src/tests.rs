@@ -1,12 +1,1343 @@
-use crate::{expr::Expr, number::Number};
+use std::collections::HashMap;
+
+use crate::{
+    diff::DerivativeError,
+    env::{DynEnv, ExprEnv},
+    expr::{DynExpr, Expr},
+    func::Arity,
+    number::{FloatMode, IntegerMode, Number},
+    parser::{ParserError, Span},
+    EvalError, Value,
+};
+
+#[cfg(feature = "derive")]
+use crate::vars::ExprVars;
 
 fn should_equal<T: Number + PartialEq>(expr: &str, val: T) {
-    assert_eq!(Expr::<T, 0>::compile(expr).unwrap().evaluate_blank().unwrap(), val);
+    assert_eq!(
+        Expr::<T, 0>::compile(expr)
+            .unwrap()
+            .evaluate_blank()
+            .unwrap(),
+        val
+    );
 }
 
 #[test]
 fn test() {
     should_equal("1 + 1", 2);
-    Expr::<i32, 0>::compile("1 / 0").unwrap().evaluate_blank().expect_err("divide by zero");
+    Expr::<i32, 0>::compile("1 / 0")
+        .unwrap()
+        .evaluate_blank()
+        .expect_err("divide by zero");
     should_equal("6.5*7.8^2.3 + (3.5^3+7/2)^3 -(5*4/(2-3))*4 + 6.5*7.8^2.3 + (3.5^3+7/2)^3 -(5*4/(2-3))*4 + 6.5*7.8^2.3 + (3.5^3+7/2)^3 -(5*4/(2-3))*4 + 6.5*7.8^2.3 + (3.5^3+7/2)^3 -(5*4/(2-3))*4", 402193.3186140596f64);
 }
+
+#[test]
+fn comparison() {
+    should_equal("1 < 2", 1);
+    should_equal("2 <= 1", 0);
+    should_equal("3 > 2 && 2 >= 2", 1);
+    should_equal("1 == 2 || 3 != 4", 1);
+    should_equal("!(1 == 1)", 0);
+    should_equal("1 + 1 == 2", 1.0);
+    should_equal("1 < 2 == 1", 1);
+
+    // The right operand is only evaluated if the left doesn't decide the result
+    should_equal("0 && 1 / 0", 0);
+    should_equal("2 || 1 / 0", 1);
+    let env = ExprEnv::new(["x"]);
+    let expr = Expr::<i32, 1>::compile_env("x != 0 && 10 / x > 1 || x == 0 || 1 % x", env).unwrap();
+    assert_eq!(expr.evaluate(&[0]).unwrap(), 1);
+    assert_eq!(expr.evaluate(&[5]).unwrap(), 1);
+    assert_eq!(expr.evaluate(&[20]).unwrap(), 1);
+    assert_eq!(expr.evaluate(&[-1]).unwrap(), 0);
+    let expr = expr.flatten().unwrap();
+    assert_eq!(expr.evaluate(&[0]).unwrap(), 1);
+    let expr = Expr::<i32, 1>::compile_env("x && 1 / 0", ExprEnv::new(["x"])).unwrap();
+    assert_eq!(expr.flatten().unwrap().evaluate(&[0]).unwrap(), 0);
+}
+
+#[test]
+fn conditional() {
+    should_equal("1 < 2 ? 3 : 4", 3);
+    should_equal("1 > 2 ? 3 : 4", 4);
+    should_equal("0 ? 1 : 0 ? 2 : 3", 3);
+    should_equal("1 ? 0 ? 1 : 2 : 3", 2);
+    should_equal("(1 ? 2 : 3) + 1", 3);
+    should_equal("if(1 == 1, 5, 6)", 5);
+    should_equal("0 ? 1 / 0 : 7", 7);
+
+    let env = ExprEnv::new(["x"]);
+    let expr = Expr::<i32, 1>::compile_env("x == 0 ? 0 : 10 / x", env).unwrap();
+    assert_eq!(expr.evaluate(&[0]).unwrap(), 0);
+    assert_eq!(expr.evaluate(&[5]).unwrap(), 2);
+    let expr = expr.flatten().unwrap();
+    assert_eq!(expr.evaluate(&[0]).unwrap(), 0);
+    Expr::<i32, 0>::compile("1 ? 1 / 0 : 1")
+        .unwrap()
+        .flatten()
+        .expect_err("divide by zero");
+}
+
+#[test]
+fn precedence() {
+    should_equal("2 ^ 3 ^ 2", 512);
+    should_equal("(2 ^ 3) ^ 2", 64);
+    should_equal("-2 ^ 2", -4);
+    should_equal("(-2) ^ 2", 4);
+    should_equal("2 ^ -1", 0.5);
+    should_equal("-2 * 3", -6);
+    should_equal("2 * -3", -6);
+    should_equal("--2", 2);
+    should_equal("10 - 4 - 3", 3);
+    should_equal("2 - -2 ^ 2", 6);
+    should_equal("!0 + 1", 2);
+}
+
+#[test]
+fn whitespace_separates_tokens() {
+    should_equal(" 1 +\t2\n", 3);
+    assert_eq!(
+        Expr::<i32, 0>::compile("1 2").unwrap_err(),
+        ParserError::ExpectedToken(Span { start: 2, end: 3 }, "operator")
+    );
+    let env = ExprEnv::default().with_trig();
+    assert_eq!(
+        Expr::<f64, 0>::compile_env("si n(1)", env).unwrap_err(),
+        ParserError::UnknownName(Span { start: 0, end: 2 }, "si".into(), Some("sin".into()))
+    );
+    assert_eq!(
+        Expr::<i32, 0>::compile("(1 +  2").unwrap_err(),
+        ParserError::ExpectedChar(Span { start: 7, end: 7 }, ')')
+    );
+    should_equal("1<=2", 1);
+    should_equal("2<-1", 0);
+}
+
+#[test]
+fn empty_input() {
+    assert_eq!(
+        Expr::<i32, 0>::compile("").unwrap_err(),
+        ParserError::EmptyInput(Span { start: 0, end: 0 })
+    );
+    let err = Expr::<i32, 0>::compile(" \t").unwrap_err();
+    assert_eq!(err, ParserError::EmptyInput(Span { start: 0, end: 2 }));
+    assert_eq!(err.to_string(), "empty expression at offset 0");
+    // A missing operand is reported where it was expected rather than as empty input
+    assert_eq!(
+        Expr::<i32, 0>::compile("1 + ").unwrap_err(),
+        ParserError::ExpectedToken(Span { start: 4, end: 4 }, "name")
+    );
+}
+
+#[test]
+fn diagnostics() {
+    let source = "1 +\n  coss(x) * 2";
+    let env = ExprEnv::<f64, 1>::new(["x"]).with_trig();
+    let err = Expr::compile_env(source, env).unwrap_err();
+    assert_eq!(err.line_col(source), Some((2, 3)));
+    assert_eq!(
+        err.to_string(),
+        "unknown name `coss`, did you mean `cos`? at offset 6"
+    );
+    assert_eq!(
+        err.render(source),
+        "error: unknown name `coss`, did you mean `cos`?\n --> 2:3\n  |\n2 |   coss(x) * 2\n  |   ^^^^\n"
+    );
+
+    let err = Expr::<f64, 0>::compile("(1 + 2").unwrap_err();
+    assert_eq!(err.to_string(), "expected `)` at offset 6");
+    let err = Expr::<f64, 0>::compile("1 + 2)").unwrap_err();
+    assert_eq!(err.to_string(), "expected end of input at offset 5");
+    let env = ExprEnv::<f64, 1>::new(["x"]);
+    let err = Expr::compile_env("tan(x)", env).unwrap_err();
+    assert_eq!(err.to_string(), "unknown name `tan` at offset 0");
+}
+
+#[test]
+fn eval_error_context() {
+    let env = ExprEnv::new(["x", "y"]);
+    let expr = Expr::<i32, 2>::compile_env("1 + (x - 2) / y", env).unwrap();
+    let err = expr.evaluate(&[5, 0]).unwrap_err();
+    assert!(matches!(err.root(), EvalError::DivideByZero));
+    let context = err.context().unwrap();
+    assert_eq!(context.span, Span { start: 4, end: 15 });
+    assert_eq!(context.operation, "/");
+    assert_eq!(context.operands, ["3", "0"]);
+    assert_eq!(
+        err.to_string(),
+        "division by zero in `/` at offset 4 (operands: 3, 0)"
+    );
+
+    let err = Expr::<u8, 0>::compile("200 + 100")
+        .unwrap()
+        .flatten()
+        .unwrap_err();
+    assert!(matches!(err.root(), EvalError::Overflow));
+    assert_eq!(err.context().unwrap().span, Span { start: 0, end: 9 });
+}
+
+#[test]
+fn fallible_func() {
+    #[derive(Debug)]
+    struct Domain(f64);
+    impl std::fmt::Display for Domain {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} is outside the domain of log", self.0)
+        }
+    }
+    impl std::error::Error for Domain {}
+
+    let env = ExprEnv::new(["x"]).with_fallible_func("log", |[x]: [f64; 1]| {
+        if x <= 0.0 {
+            Err(Domain(x))
+        } else {
+            Ok(x.ln())
+        }
+    });
+    let expr = Expr::compile_env("1 + log(x * 2)", env).unwrap();
+    assert_eq!(expr.evaluate(&[0.5]).unwrap(), 1.0);
+    let err = expr.evaluate(&[-1.0]).unwrap_err();
+    let EvalError::Custom(inner) = err.root() else {
+        panic!("expected custom error, got {err:?}");
+    };
+    assert!(inner.is::<Domain>());
+    let context = err.context().unwrap();
+    assert_eq!(context.operation, "log");
+    assert_eq!(context.span, Span { start: 4, end: 14 });
+    assert_eq!(
+        err.to_string(),
+        "-2 is outside the domain of log in `log` at offset 4 (operands: -2.0)"
+    );
+}
+
+#[test]
+fn variadic_func() {
+    let env = || {
+        ExprEnv::new(["x"])
+            .with_variadic_func("max", 1.., |args: &[f64]| {
+                args.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+            })
+            .with_variadic_func("round", 1..=2, |args: &[f64]| {
+                let scale = 10f64.powf(*args.get(1).unwrap_or(&0.0));
+                (args[0] * scale).round() / scale
+            })
+            .with_variadic_func("zero", ..1, |_: &[f64]| 0.0)
+    };
+    let eval = |s: &str, x: f64| Expr::compile_env(s, env()).unwrap().evaluate(&[x]).unwrap();
+    assert_eq!(eval("max(x)", 1.0), 1.0);
+    assert_eq!(eval("max(1, x, 3 > 2 ? 4 : 0, 2)", 1.0), 4.0);
+    assert_eq!(eval("round(x)", 2.345), 2.0);
+    assert_eq!(eval("round(x, 2)", 2.345), 2.35);
+    assert_eq!(eval("zero() + 1", 0.0), 1.0);
+
+    let err = Expr::compile_env("round(1, 2, 3)", env()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`round` takes 1 to 2 arguments but 3 were given at offset 0"
+    );
+    let err = Expr::compile_env("max()", env()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`max` takes at least 1 argument but 0 were given at offset 0"
+    );
+    let env = ExprEnv::default().with_func("double", |[x]: [i32; 1]| x * 2);
+    let err = Expr::compile_env("double(1, 2)", env).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`double` takes 1 argument but 2 were given at offset 0"
+    );
+
+    // A range no number of arguments is in is rejected when the function is added
+    assert_eq!(Arity::from_range(0..1), Arity::exactly(0));
+    for (start, end) in [(0, 0), (5, 3)] {
+        let add = || ExprEnv::<i32, 0>::default().with_variadic_func("f", start..end, |_| 0);
+        assert!(std::panic::catch_unwind(add).is_err());
+    }
+    let (start, end) = (5, 4);
+    assert!(std::panic::catch_unwind(|| Arity::from_range(start..=end)).is_err());
+    assert_eq!(Arity::from_range(start..=end + 1), Arity::exactly(5));
+}
+
+#[test]
+fn std_math() {
+    let eval = |s: &str| {
+        let env = ExprEnv::new(["x"]).with_std_math();
+        Expr::<f64, 1>::compile_env(s, env)
+            .unwrap()
+            .evaluate(&[2.0])
+            .unwrap()
+    };
+    assert_eq!(eval("sqrt(x * 8)"), 4.0);
+    assert_eq!(eval("cbrt(27)"), 3.0);
+    assert_eq!(eval("hypot(3, 4)"), 5.0);
+    assert_eq!(eval("log(8, x)"), 3.0);
+    assert_eq!(eval("log2(8) + log10(100) + ln(exp(1))"), 6.0);
+    assert_eq!(
+        eval("floor(-1.5) + ceil(1.2) + round(2.5) + trunc(-2.7)"),
+        1.0
+    );
+    assert_eq!(eval("abs(-x) * sign(-3) + sign(0)"), -2.0);
+    assert_eq!(eval("min(3, x, 5) + max(3, x, 5) + clamp(10, 0, x)"), 9.0);
+    assert_eq!(eval("atan2(1, 1)"), std::f64::consts::FRAC_PI_4);
+    assert_eq!(
+        eval("asin(1) + acos(1) + atan(0)"),
+        std::f64::consts::FRAC_PI_2
+    );
+    assert_eq!(
+        eval("sinh(0) + cosh(0) + tanh(0) + asinh(0) + acosh(1) + atanh(0)"),
+        1.0
+    );
+
+    let env = ExprEnv::new(["x"])
+        .with_min_max()
+        .with_sign()
+        .with_rounding();
+    let expr = Expr::<i32, 1>::compile_env("max(abs(x), 3) * sign(x) + round(x)", env).unwrap();
+    assert_eq!(expr.evaluate(&[-5]).unwrap(), -10);
+    let err = expr.evaluate(&[i32::MIN]).unwrap_err();
+    assert!(matches!(err.root(), EvalError::Overflow));
+    assert_eq!(err.context().unwrap().operation, "abs");
+
+    // Names may contain digits after the first letter, so the presets above can be called
+    let env = ExprEnv::new(["x2"]).with_std_math();
+    let expr = Expr::<f64, 1>::compile_env("log2(x2) + log10(100)", env).unwrap();
+    assert_eq!(expr.evaluate(&[8.0]).unwrap(), 5.0);
+    let env = ExprEnv::new(["x"]);
+    assert_eq!(
+        Expr::<f64, 1>::compile_env("2x", env).unwrap_err(),
+        ParserError::ExpectedToken(Span { start: 1, end: 2 }, "operator")
+    );
+}
+
+#[test]
+fn constants() {
+    let env = ExprEnv::new(["r"])
+        .with_math_constants()
+        .with_const("two", 2.0);
+    let expr = Expr::<f64, 1>::compile_env("two * pi * r", env).unwrap();
+    assert_eq!(expr.evaluate(&[1.0]).unwrap(), std::f64::consts::TAU);
+    should_equal_env("tau - 2 * pi", 0.0);
+    should_equal_env("phi * phi - phi", 1.0);
+    should_equal_env("-inf < e", 1.0);
+    should_equal_env("nan == nan", 0.0);
+
+    let env = ExprEnv::default().with_const("answer", 42);
+    let expr = Expr::<i32, 0>::compile_env("answer / 2", env)
+        .unwrap()
+        .flatten()
+        .unwrap();
+    assert!(matches!(expr.value, Value::Constant(21)));
+}
+
+fn should_equal_env(expr: &str, val: f64) {
+    let env = ExprEnv::default().with_math_constants();
+    let result: f64 = Expr::compile_env(expr, env)
+        .unwrap()
+        .evaluate_blank()
+        .unwrap();
+    assert!((result - val).abs() < 1e-12, "{expr} = {result}");
+}
+
+#[test]
+fn identifiers() {
+    let env = ExprEnv::new(["x1", "max_speed", "_tmp", "player.health"]);
+    let expr = Expr::<i32, 4>::compile_env("x1 + max_speed * _tmp - player.health", env).unwrap();
+    assert_eq!(expr.evaluate(&[1, 2, 3, 4]).unwrap(), 3);
+
+    let env = ExprEnv::new(["x"]).with_namespace("math.int", |ns| {
+        ns.with_fallible_func("div", |[a, b]: [i32; 2]| {
+            a.checked_div(b).ok_or("division by zero")
+        })
+        .with_const("ten", 10)
+    });
+    let expr = Expr::<i32, 1>::compile_env("math.int.div(math.int.ten, x)", env).unwrap();
+    assert_eq!(expr.evaluate(&[2]).unwrap(), 5);
+    let err = expr.evaluate(&[0]).unwrap_err();
+    assert_eq!(err.context().unwrap().operation, "math.int.div");
+
+    // Only names are scoped, so modes and operators can't be set within a namespace
+    let wrapping = || {
+        ExprEnv::<i32, 0>::default()
+            .with_namespace("w", |w| w.with_integer_mode(IntegerMode::Wrapping))
+    };
+    assert!(std::panic::catch_unwind(wrapping).is_err());
+    let bitwise = || ExprEnv::<i32, 0>::default().with_namespace("b", |b| b.with_bitwise());
+    assert!(std::panic::catch_unwind(bitwise).is_err());
+    let env = ExprEnv::<i32, 0>::default()
+        .with_integer_mode(IntegerMode::Saturating)
+        .with_namespace("s", |s| s.with_const("max", i32::MAX));
+    let expr = Expr::compile_env("s.max + 1", env).unwrap();
+    assert_eq!(expr.evaluate_blank().unwrap(), i32::MAX);
+
+    let env = ExprEnv::new(["a"]);
+    let err = Expr::<i32, 1>::compile_env("a.b", env).unwrap_err();
+    assert!(matches!(err, ParserError::UnknownName(_, name, _) if name == "a.b"));
+    should_equal("1.5 + 2.", 3.5);
+
+    // Names are ASCII only
+    let err = Expr::compile_env("π * 2", ExprEnv::<f64, 1>::new(["π"])).unwrap_err();
+    assert_eq!(
+        err,
+        ParserError::ExpectedToken(Span { start: 0, end: 2 }, "name")
+    );
+    let err = Expr::compile_env("xé", ExprEnv::<f64, 1>::new(["x"])).unwrap_err();
+    assert_eq!(
+        err,
+        ParserError::ExpectedToken(Span { start: 1, end: 3 }, "operator")
+    );
+}
+
+#[test]
+fn literals() {
+    should_equal("1e-3 * 1E3", 1.0);
+    should_equal("6.02e23", 6.02e23);
+    should_equal("2.5e+2", 250.0);
+    should_equal("0xFF + 0b1010 + 0o17", 280);
+    should_equal("1_000_000 + 0xFF_FF", 1_065_535u32);
+    should_equal("1_000.5", 1000.5);
+    should_equal("0xFFFF_FFFF_FFFF_FFFF", u64::MAX);
+
+    let err = Expr::<u8, 0>::compile("1 + 300").unwrap_err();
+    assert_eq!(
+        err,
+        ParserError::LiteralOutOfRange(Span { start: 4, end: 7 }, "300".into(), "u8")
+    );
+    assert_eq!(
+        err.to_string(),
+        "literal `300` does not fit in type `u8` at offset 4"
+    );
+    let err = Expr::<u8, 0>::compile("0x100").unwrap_err();
+    assert!(matches!(err, ParserError::LiteralOutOfRange(..)));
+    let err = Expr::<i32, 0>::compile("1.5").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "invalid literal `1.5` for type `i32` at offset 0"
+    );
+    let err = Expr::<i32, 0>::compile("0xZZ").unwrap_err();
+    assert!(matches!(err, ParserError::InvalidLiteral(_, literal, _) if literal == "0xZZ"));
+    let err = Expr::<f64, 0>::compile("0xFF").unwrap_err();
+    assert!(matches!(err, ParserError::InvalidLiteral(..)));
+}
+
+#[test]
+fn bitwise() {
+    let eval = |s: &str, x: u32| {
+        let env = ExprEnv::new(["x"]).with_bitwise();
+        Expr::compile_env(s, env).unwrap().evaluate(&[x])
+    };
+    assert_eq!(eval("x & 0xF0 | 0x0F", 0xAB).unwrap(), 0xAF);
+    assert_eq!(eval("x xor 0xFF", 0x0F).unwrap(), 0xF0);
+    assert_eq!(eval("~x", 0).unwrap(), u32::MAX);
+    assert_eq!(eval("1 << x + 1", 3).unwrap(), 16);
+    assert_eq!(eval("x >> 1 == 2", 5).unwrap(), 1);
+    assert_eq!(eval("x | 1 xor 3 & 6", 8).unwrap(), 8 | (1 ^ (3 & 6)));
+    let err = eval("1 << x", 32).unwrap_err();
+    assert!(matches!(err.root(), EvalError::Overflow));
+    assert_eq!(err.context().unwrap().operation, "<<");
+
+    let env = ExprEnv::new(["x"]).with_bitwise();
+    let expr = Expr::<i64, 1>::compile_env("1 >> x", env).unwrap();
+    assert!(matches!(
+        expr.evaluate(&[-1]).unwrap_err().root(),
+        EvalError::Overflow
+    ));
+    Expr::<i64, 0>::compile("1 << 2").expect_err("bitwise operators are opt-in");
+}
+
+#[test]
+fn checked_pow() {
+    should_equal("2 ^ 10", 1024u16);
+    should_equal("3 ^ 0", 1u8);
+    let overflow =
+        |expr: Result<i64, EvalError>| matches!(expr.unwrap_err().root(), EvalError::Overflow);
+    assert!(overflow(Expr::compile("2 ^ 63").unwrap().evaluate_blank()));
+    assert!(overflow(
+        Expr::compile("2 ^ 5000000000").unwrap().evaluate_blank()
+    ));
+    should_equal("0 ^ 5000000000", 0i64);
+    should_equal("1 ^ 5000000000", 1i64);
+    should_equal("(-1) ^ 5000000000", 1i64);
+    should_equal("(-1) ^ 5000000001", -1i64);
+    let err = Expr::<i32, 0>::compile("2 ^ -1")
+        .unwrap()
+        .evaluate_blank()
+        .unwrap_err();
+    assert!(matches!(err.root(), EvalError::NegativeIntegerExponent));
+
+    should_equal("2 ^ 127", 1u128 << 127);
+    should_equal("3 ^ 3 - 30", -3isize);
+    should_equal("0xFF ^ 2", 65025usize);
+}
+
+#[test]
+fn negation() {
+    should_equal("-5 + 10", 5i32);
+    should_equal("-0", 0u32);
+    should_equal("10 - 5", 5u32);
+
+    let err = Expr::<u32, 0>::compile("1 + -5").unwrap_err();
+    assert_eq!(
+        err,
+        ParserError::LiteralOutOfRange(Span { start: 4, end: 6 }, "-5".into(), "u32")
+    );
+    assert_eq!(
+        err.to_string(),
+        "literal `-5` does not fit in type `u32` at offset 4"
+    );
+
+    // Saturating negation would quietly turn the literal into 0
+    let env = ExprEnv::<u8, 0>::default().with_integer_mode(IntegerMode::Saturating);
+    let err = Expr::compile_env("-5", env).unwrap_err();
+    assert_eq!(
+        err,
+        ParserError::LiteralOutOfRange(Span { start: 0, end: 2 }, "-5".into(), "u8")
+    );
+    let env = ExprEnv::<u8, 0>::default().with_integer_mode(IntegerMode::Saturating);
+    assert_eq!(
+        Expr::compile_env("-0", env)
+            .unwrap()
+            .evaluate_blank()
+            .unwrap(),
+        0
+    );
+
+    let env = || ExprEnv::<u32, 1>::new(["x"]);
+    let expr = Expr::compile_env("-x", env()).unwrap();
+    assert_eq!(expr.evaluate(&[0]).unwrap(), 0);
+    let err = expr.evaluate(&[3]).unwrap_err();
+    assert!(matches!(err.root(), EvalError::Overflow));
+
+    let expr = Expr::compile_env("-x", ExprEnv::<i32, 1>::new(["x"])).unwrap();
+    assert_eq!(expr.evaluate(&[i32::MAX]).unwrap(), -i32::MAX);
+    let err = expr.evaluate(&[i32::MIN]).unwrap_err();
+    assert!(matches!(err.root(), EvalError::Overflow));
+    assert_eq!(err.context().unwrap().operation, "-");
+
+    // The minimum value is read as a single literal, so it can be written and printed
+    should_equal("-2147483648", i32::MIN);
+    should_equal("-128 + 1", -127i8);
+    should_equal("(-128) ^ 1", i8::MIN);
+    should_equal("-0x80", i8::MIN);
+    should_equal("-0b1000_0000", i8::MIN);
+    should_equal("-0x8000_0000", i32::MIN);
+    let err = Expr::<i8, 0>::compile("-0x81").unwrap_err();
+    assert_eq!(
+        err,
+        ParserError::LiteralOutOfRange(Span { start: 1, end: 5 }, "0x81".into(), "i8")
+    );
+    let err = Expr::<i8, 0>::compile("-128 ^ 1").unwrap_err();
+    assert_eq!(
+        err,
+        ParserError::LiteralOutOfRange(Span { start: 1, end: 4 }, "128".into(), "i8")
+    );
+    let err = Expr::<i8, 0>::compile("--128").unwrap().evaluate_blank();
+    assert!(matches!(err.unwrap_err().root(), EvalError::Overflow));
+    let expr = Expr::compile_env("x * (-2147483647 - 1)", ExprEnv::<i32, 1>::new(["x"])).unwrap();
+    let expr = expr.flatten().unwrap();
+    assert_eq!(expr.to_string(), "x * -2147483648");
+    let printed = Expr::compile_env(expr.to_string(), ExprEnv::new(["x"])).unwrap();
+    assert_eq!(printed.ast(), expr.ast());
+}
+
+#[test]
+fn integer_modes() {
+    let compile = |source: &str, mode: IntegerMode| {
+        let env = ExprEnv::<i8, 1>::new(["x"]).with_integer_mode(mode);
+        Expr::compile_env(source, env).unwrap()
+    };
+    let overflow =
+        |result: Result<i8, EvalError>| matches!(result.unwrap_err().root(), EvalError::Overflow);
+
+    assert!(overflow(
+        compile("x + 1", IntegerMode::Checked).evaluate(&[127])
+    ));
+    assert_eq!(
+        compile("x + 1", IntegerMode::Wrapping)
+            .evaluate(&[127])
+            .unwrap(),
+        -128
+    );
+    assert_eq!(
+        compile("x + 1", IntegerMode::Saturating)
+            .evaluate(&[127])
+            .unwrap(),
+        127
+    );
+    assert_eq!(
+        compile("x - 1", IntegerMode::Saturating)
+            .evaluate(&[-128])
+            .unwrap(),
+        -128
+    );
+
+    assert!(overflow(
+        compile("x / -1", IntegerMode::Checked).evaluate(&[-128])
+    ));
+    assert_eq!(
+        compile("x / -1", IntegerMode::Wrapping)
+            .evaluate(&[-128])
+            .unwrap(),
+        -128
+    );
+    assert_eq!(
+        compile("x / -1", IntegerMode::Saturating)
+            .evaluate(&[-128])
+            .unwrap(),
+        127
+    );
+    assert_eq!(
+        compile("x % -1", IntegerMode::Saturating)
+            .evaluate(&[-128])
+            .unwrap(),
+        0
+    );
+    for mode in [
+        IntegerMode::Checked,
+        IntegerMode::Wrapping,
+        IntegerMode::Saturating,
+    ] {
+        let err = compile("1 / x", mode).evaluate(&[0]).unwrap_err();
+        assert!(matches!(err.root(), EvalError::DivideByZero));
+    }
+
+    assert!(overflow(
+        compile("-x", IntegerMode::Checked).evaluate(&[-128])
+    ));
+    assert_eq!(
+        compile("-x", IntegerMode::Wrapping)
+            .evaluate(&[-128])
+            .unwrap(),
+        -128
+    );
+    assert_eq!(
+        compile("-x", IntegerMode::Saturating)
+            .evaluate(&[-128])
+            .unwrap(),
+        127
+    );
+
+    assert_eq!(
+        compile("x ^ 8", IntegerMode::Wrapping)
+            .evaluate(&[3])
+            .unwrap(),
+        3i8.wrapping_pow(8)
+    );
+    assert_eq!(
+        compile("x ^ 7", IntegerMode::Saturating)
+            .evaluate(&[-3])
+            .unwrap(),
+        -128
+    );
+    assert_eq!(
+        compile("x ^ 8", IntegerMode::Saturating)
+            .evaluate(&[-3])
+            .unwrap(),
+        127
+    );
+
+    // Negative literals wrap rather than being rejected for unsigned types
+    let env = ExprEnv::<u32, 0>::default().with_integer_mode(IntegerMode::Wrapping);
+    let expr = Expr::compile_env("-1", env).unwrap();
+    assert_eq!(expr.evaluate_blank().unwrap(), u32::MAX);
+    let env = ExprEnv::<u64, 0>::default().with_integer_mode(IntegerMode::Wrapping);
+    let expr = Expr::compile_env("3 ^ 5000000001", env).unwrap();
+    let expected = 3u64
+        .wrapping_pow(2500000000)
+        .wrapping_pow(2)
+        .wrapping_mul(3);
+    assert_eq!(expr.evaluate_blank().unwrap(), expected);
+}
+
+#[test]
+fn float_modes() {
+    let compile = |source: &str, mode: FloatMode| {
+        let env = ExprEnv::<f64, 1>::new(["x"])
+            .with_float_mode(mode)
+            .with_std_math()
+            .with_func("nan", |[]: [f64; 0]| f64::NAN);
+        Expr::compile_env(source, env).unwrap()
+    };
+    let non_finite = |result: Result<f64, EvalError>| {
+        let err = result.unwrap_err();
+        assert!(matches!(err.root(), EvalError::NonFinite));
+        err.context().unwrap().operation.clone()
+    };
+
+    assert!(compile("x ^ -1", FloatMode::Standard)
+        .evaluate(&[0.0])
+        .unwrap()
+        .is_infinite());
+    assert_eq!(
+        non_finite(compile("x ^ -1", FloatMode::Strict).evaluate(&[0.0])),
+        "^"
+    );
+    let inf = f64::INFINITY;
+    assert!(compile("x - x", FloatMode::Standard)
+        .evaluate(&[inf])
+        .unwrap()
+        .is_nan());
+    // Variables are checked before evaluating, and constants when compiling
+    let err = compile("x - x", FloatMode::Strict)
+        .evaluate(&[inf])
+        .unwrap_err();
+    assert!(matches!(err, EvalError::NonFinite));
+    let constants = || {
+        ExprEnv::<f64, 0>::default()
+            .with_float_mode(FloatMode::Strict)
+            .with_math_constants()
+    };
+    assert_eq!(
+        Expr::compile_env("1 + inf", constants()).unwrap_err(),
+        ParserError::NonFiniteConstant(Span { start: 4, end: 7 }, "inf".into())
+    );
+    assert_eq!(
+        Expr::compile_env("1e400", constants()).unwrap_err(),
+        ParserError::LiteralOutOfRange(Span { start: 0, end: 5 }, "1e400".into(), "f64")
+    );
+    let err = Expr::from_ast(crate::ast::Node::Constant(f64::NAN), constants()).unwrap_err();
+    assert!(matches!(err, ParserError::NonFiniteConstant(_, name) if name == "NaN"));
+    assert!(
+        Expr::compile_env("nan", ExprEnv::<f64, 0>::default().with_math_constants())
+            .unwrap()
+            .evaluate_blank()
+            .unwrap()
+            .is_nan()
+    );
+    assert_eq!(
+        non_finite(compile("1 + nan()", FloatMode::Strict).evaluate(&[0.0])),
+        "nan"
+    );
+    assert_eq!(
+        non_finite(compile("sqrt(x)", FloatMode::Strict).evaluate(&[-1.0])),
+        "sqrt"
+    );
+    assert_eq!(
+        non_finite(compile("x * 10", FloatMode::Strict).evaluate(&[f64::MAX])),
+        "*"
+    );
+    assert_eq!(
+        compile("sqrt(x) + 1", FloatMode::Strict)
+            .evaluate(&[4.0])
+            .unwrap(),
+        3.0
+    );
+
+    for mode in [FloatMode::Standard, FloatMode::Strict] {
+        let err = compile("1 / x", mode).evaluate(&[0.0]).unwrap_err();
+        assert!(matches!(err.root(), EvalError::DivideByZero));
+    }
+    assert_eq!(
+        compile("1 / x", FloatMode::Ieee).evaluate(&[-0.0]).unwrap(),
+        -inf
+    );
+    assert!(compile("x / x", FloatMode::Ieee)
+        .evaluate(&[0.0])
+        .unwrap()
+        .is_nan());
+    assert!(compile("1 % x", FloatMode::Ieee)
+        .evaluate(&[0.0])
+        .unwrap()
+        .is_nan());
+
+    // Integers are unaffected
+    let env = ExprEnv::<i32, 1>::new(["x"]).with_float_mode(FloatMode::Ieee);
+    let err = Expr::compile_env("1 / x", env)
+        .unwrap()
+        .evaluate(&[0])
+        .unwrap_err();
+    assert!(matches!(err.root(), EvalError::DivideByZero));
+}
+
+#[test]
+fn dyn_expr() {
+    let names: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+    let env = DynEnv::new(names).with(|env| {
+        env.with_std_math()
+            .with_const("k", 10.0)
+            .with_float_mode(FloatMode::Strict)
+    });
+    let expr = DynExpr::compile_env("sqrt(a) + b * k - if(c, 1, 0)", env).unwrap();
+    assert_eq!(expr.var_count(), 3);
+    assert_eq!(expr.evaluate(&[9.0, 2.0, 1.0]).unwrap(), 22.0);
+    let err = expr.evaluate(&[-1.0, 2.0, 1.0]).unwrap_err();
+    assert!(matches!(err.root(), EvalError::NonFinite));
+
+    let err = expr.evaluate(&[9.0, 2.0]).unwrap_err();
+    assert!(matches!(
+        err,
+        EvalError::VariableCount {
+            expected: 3,
+            given: 2
+        }
+    ));
+    assert_eq!(
+        err.to_string(),
+        "expected 3 variable values but 2 were given"
+    );
+
+    let err = DynExpr::<f64>::compile_env("d", DynEnv::new(vec!["a".into()])).unwrap_err();
+    assert!(matches!(err, ParserError::UnknownName(_, name, _) if name == "d"));
+
+    let expr = DynExpr::compile_env("2 * 3 + x", DynEnv::new(vec!["x".into()]))
+        .unwrap()
+        .flatten()
+        .unwrap();
+    assert!(matches!(expr.value, Value::BinaryOperation(..)));
+    assert_eq!(expr.evaluate(&[1]).unwrap(), 7);
+}
+
+#[cfg(feature = "derive")]
+#[derive(ExprVars)]
+struct Player {
+    health: f64,
+    #[expr(rename = "lvl")]
+    level: u8,
+    armor: f32,
+    #[expr(skip)]
+    #[allow(dead_code)]
+    name: String,
+}
+
+// Fields are cloned out, so they need no bounds beyond those the derive adds
+#[cfg(feature = "derive")]
+#[derive(ExprVars)]
+struct Pair<T> {
+    first: T,
+    second: T,
+}
+
+#[test]
+fn named_vars() {
+    let env = || ExprEnv::<f64, 3>::new(["health", "lvl", "armor"]);
+    let expr = Expr::compile_env("health * (1 + lvl / 10) + armor", env()).unwrap();
+
+    let vars = HashMap::from([("health", 100.0), ("lvl", 5.0), ("armor", 2.5)]);
+    assert_eq!(expr.evaluate_with(&vars).unwrap(), 152.5);
+    let vars: HashMap<String, f64> = vars.into_iter().map(|(k, v)| (k.into(), v)).collect();
+    assert_eq!(expr.evaluate_with(&vars).unwrap(), 152.5);
+
+    let env = DynEnv::<i64>::new(vec!["second".into(), "first".into()]);
+    let expr = DynExpr::compile_env("first - second", env).unwrap();
+    let vars = HashMap::from([("first", 10), ("second", 3)]);
+    assert_eq!(expr.evaluate_with(&vars).unwrap(), 7);
+}
+
+#[cfg(feature = "derive")]
+#[test]
+fn derived_vars() {
+    let env = ExprEnv::<f64, 3>::new(["health", "lvl", "armor"]);
+    let expr = Expr::compile_env("health * (1 + lvl / 10) + armor", env).unwrap();
+    let player = Player {
+        health: 100.0,
+        level: 5,
+        armor: 2.5,
+        name: "Ada".into(),
+    };
+    assert_eq!(expr.evaluate_with(&player).unwrap(), 152.5);
+
+    let expr = Expr::compile_env("health + name", ExprEnv::<f64, 2>::new(["health", "name"]));
+    let err = expr.unwrap().evaluate_with(&player).unwrap_err();
+    assert!(matches!(&err, EvalError::MissingVariable(name) if name == "name"));
+    assert_eq!(err.to_string(), "no value for variable `name`");
+
+    let env = DynEnv::<i64>::new(vec!["second".into(), "first".into()]);
+    let expr = DynExpr::compile_env("first - second", env).unwrap();
+    let pair = Pair {
+        first: 10,
+        second: 3,
+    };
+    assert_eq!(expr.evaluate_with(&pair).unwrap(), 7);
+}
+
+#[test]
+fn used_names() {
+    let env = ExprEnv::<f64, 4>::new(["a", "b", "c", "unused"])
+        .with_std_math()
+        .with_namespace("stats", |stats| {
+            stats.with_variadic_func("mean", 1.., |args: &[f64]| {
+                args.iter().sum::<f64>() / args.len() as f64
+            })
+        });
+    let expr = Expr::compile_env("c > 0 ? stats.mean(b, abs(b)) : a + a", env).unwrap();
+    assert_eq!(expr.used_variables(), ["a", "b", "c"]);
+    assert_eq!(expr.used_functions(), ["abs", "stats.mean"]);
+
+    let expr = Expr::<f64, 0>::compile("1 + 2").unwrap();
+    assert!(expr.used_variables().is_empty());
+    assert!(expr.used_functions().is_empty());
+
+    let env = DynEnv::<i32>::new(vec!["x".into(), "y".into()]).with(|env| env.with_min_max());
+    let expr = DynExpr::compile_env("min(y, 3) * y", env).unwrap();
+    assert_eq!(expr.used_variables(), ["y"]);
+    assert_eq!(expr.used_functions(), ["min"]);
+}
+
+#[test]
+fn ast() {
+    use crate::ast::{BinaryOp, Fold, Node, UnaryOp, Visitor};
+
+    let env = ExprEnv::<i64, 2>::new(["x", "y"])
+        .with_min_max()
+        .with_bitwise();
+    let expr = Expr::compile_env("if(x < 0, -x, min(x, y) xor ~2) ^ 2", env).unwrap();
+    let x = || Node::Variable(0, "x".into());
+    let y = Node::Variable(1, "y".into());
+    let condition = Node::Binary(BinaryOp::Lt, Box::new([x(), Node::Constant(0)]));
+    let negated = Node::Unary(UnaryOp::Neg, Box::new(x()));
+    let min = Node::Call("min".into(), vec![x(), y]);
+    let not_two = Node::Unary(UnaryOp::BitNot, Box::new(Node::Constant(2)));
+    let xor = Node::Binary(BinaryOp::BitXor, Box::new([min, not_two]));
+    let conditional = Node::Conditional(Box::new([condition, negated, xor]));
+    let expected = Node::Binary(BinaryOp::Pow, Box::new([conditional, Node::Constant(2)]));
+    assert_eq!(expr.ast(), expected);
+
+    struct Names(Vec<String>);
+    impl Visitor<i64> for Names {
+        fn visit_variable(&mut self, _index: usize, name: &str) {
+            self.0.push(name.to_owned());
+        }
+
+        fn visit_call(&mut self, name: &str, args: &[Node<i64>]) {
+            self.0.push(format!("{name}()"));
+            args.iter().for_each(|arg| self.visit(arg));
+        }
+    }
+    let mut names = Names(Vec::new());
+    names.visit(&expr.ast());
+    assert_eq!(names.0, ["x", "x", "min()", "x", "y"]);
+
+    // Swap the operands of every subtraction
+    struct Swap;
+    impl Fold<i64> for Swap {
+        fn fold_binary(&mut self, op: BinaryOp, left: Node<i64>, right: Node<i64>) -> Node<i64> {
+            let (left, right) = (self.fold(left), self.fold(right));
+            match op {
+                BinaryOp::Sub => Node::Binary(op, Box::new([right, left])),
+                _ => Node::Binary(op, Box::new([left, right])),
+            }
+        }
+    }
+    let env = ExprEnv::<i64, 2>::new(["x", "y"]);
+    let swapped = Swap.fold(Expr::compile_env("(x - y) * (1 - x)", env).unwrap().ast());
+    let env = ExprEnv::<i64, 2>::new(["x", "y"]);
+    let expected = Expr::compile_env("(y - x) * (x - 1)", env).unwrap().ast();
+    assert_eq!(swapped, expected);
+
+    // A rewritten tree compiles back into an expression against an environment
+    let swapped = Expr::from_ast(swapped, ExprEnv::<i64, 2>::new(["x", "y"])).unwrap();
+    assert_eq!(swapped.evaluate(&[3, 5]).unwrap(), 4);
+    let env =
+        || DynEnv::new(vec!["x".into(), "y".into()]).with(|env| env.with_min_max().with_bitwise());
+    let rebuilt = DynExpr::from_ast(expr.ast(), env()).unwrap();
+    assert_eq!(rebuilt.to_string(), expr.to_string());
+    assert_eq!(
+        rebuilt.evaluate(&[2, 7]).unwrap(),
+        expr.evaluate(&[2, 7]).unwrap()
+    );
+    let err = DynExpr::from_ast(expr.ast(), DynEnv::new(vec!["x".into(), "y".into()]));
+    assert!(matches!(err, Err(ParserError::UnknownName(_, name, _)) if name == "xor"));
+    let call = Node::Call("min".into(), vec![]);
+    let err = DynExpr::from_ast(call, env()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`min` takes at least 1 argument but 0 were given at offset 0"
+    );
+}
+
+#[test]
+fn print() {
+    let env = || {
+        ExprEnv::<i64, 3>::new(["x", "y", "z"])
+            .with_min_max()
+            .with_bitwise()
+    };
+    let round_trip = |source: &str, printed: &str| {
+        let expr = Expr::compile_env(source, env()).unwrap();
+        assert_eq!(expr.to_string(), printed);
+        let reparsed = Expr::compile_env(expr.to_string(), env()).unwrap();
+        assert_eq!(reparsed.ast(), expr.ast());
+    };
+    round_trip("x+y*z", "x + y * z");
+    round_trip("(x + y) * z", "(x + y) * z");
+    round_trip("x - (y - z)", "x - (y - z)");
+    round_trip("(x - y) - z", "x - y - z");
+    round_trip("x ^ (y ^ z)", "x ^ y ^ z");
+    round_trip("(x ^ y) ^ z", "(x ^ y) ^ z");
+    round_trip("-x ^ 2", "-x ^ 2");
+    round_trip("(-x) ^ 2", "(-x) ^ 2");
+    round_trip("x ^ -y", "x ^ -y");
+    round_trip("x * -(y + z)", "x * -(y + z)");
+    round_trip("- -x", "--x");
+    round_trip("!(x < y) && (y || z)", "!(x < y) && (y || z)");
+    round_trip("x | y xor z & ~x << 1", "x | y xor z & ~x << 1");
+    round_trip("((x | y) xor z) & x", "((x | y) xor z) & x");
+    round_trip(
+        "x > 0 ? min(x, y + 1) : max(z)",
+        "if(x > 0, min(x, y + 1), max(z))",
+    );
+    round_trip("if(x, y, z) * 2", "if(x, y, z) * 2");
+
+    // Folded constants may be negative, and must keep their sign bound to them
+    let expr = Expr::compile_env("(0 - 2) ^ x + y * (1 - 4)", env()).unwrap();
+    let flat = expr.clone().flatten().unwrap();
+    assert_eq!(flat.to_string(), "(-2) ^ x + y * -3");
+    let reparsed = Expr::compile_env(flat.to_string(), env()).unwrap();
+    for vars in [[3, 5, 0], [2, -1, 0]] {
+        assert_eq!(
+            reparsed.evaluate(&vars).unwrap(),
+            expr.evaluate(&vars).unwrap()
+        );
+    }
+
+    let expr = Expr::compile_env("x * 0.5 + 1e-7", ExprEnv::<f64, 1>::new(["x"])).unwrap();
+    assert_eq!(expr.to_string(), "x * 0.5 + 1e-7");
+}
+
+/// Variables `x` and `y` with functions which can and can't be differentiated
+fn calculus_env() -> ExprEnv<f64, 2> {
+    ExprEnv::new(["x", "y"])
+        .with_trig()
+        .with_logarithms()
+        .with_func("hypot", |[a, b]: [f64; 2]| a.hypot(b))
+        .with_derivative("hypot", ["a", "b"], ["a / hypot(a, b)", "b / hypot(a, b)"])
+        .with_func("floor", |[a]: [f64; 1]| a.floor())
+}
+
+#[test]
+fn derivative() {
+    let close = |expr: &Expr<f64, 2>, vars: [f64; 2], expected: f64| {
+        let val = expr.evaluate(&vars).unwrap();
+        assert!(
+            (val - expected).abs() < 1e-9,
+            "{expr} gave {val}, not {expected}"
+        );
+    };
+    let d = |source: &str, var: &str| {
+        Expr::compile_env(source, calculus_env())
+            .unwrap()
+            .derivative(var)
+            .unwrap()
+    };
+
+    close(
+        &d("x * y + x / y - x % y", "x"),
+        [5.0, 2.0],
+        2.0 + 0.5 - 1.0,
+    );
+    close(
+        &d("x * y + x / y - x % y", "y"),
+        [5.0, 2.0],
+        5.0 - 1.25 + 2.0,
+    );
+    close(&d("x ^ y", "x"), [2.0, 3.0], 12.0);
+    close(&d("x ^ y", "x"), [0.0, 0.0], 0.0);
+    close(&d("x ^ y", "y"), [2.0, 3.0], 8.0 * 2f64.ln());
+    close(&d("2 ^ x", "x"), [3.0, 0.0], 8.0 * 2f64.ln());
+    close(&d("x ^ x", "x"), [2.0, 0.0], 4.0 * (2f64.ln() + 1.0));
+    close(&d("-sin(x * y)", "x"), [0.5, 2.0], -2.0 * 1f64.cos());
+    close(&d("tan(x)", "x"), [0.3, 0.0], 1.0 / 0.3f64.cos().powi(2));
+    close(&d("hypot(x, 2 * y)", "y"), [3.0, 2.0], 4.0 / 5.0 * 2.0);
+    close(&d("x < y ? x * x : -x", "x"), [1.0, 2.0], 2.0);
+    close(&d("x < y ? x * x : -x", "x"), [3.0, 2.0], -1.0);
+    // Derivatives can be taken repeatedly, including of functions used by other derivatives
+    close(
+        &d("sin(x)", "x").derivative("x").unwrap(),
+        [1.0, 0.0],
+        -1f64.sin(),
+    );
+    close(
+        &d("x ^ y", "y").derivative("y").unwrap(),
+        [2.0, 3.0],
+        8.0 * 2f64.ln().powi(2),
+    );
+
+    // The derivative of `^` by its exponent calls the environment's `ln`, so it can be printed
+    // and compiled again
+    let derivative = d("2 ^ x", "x");
+    assert_eq!(derivative.to_string(), "2.0 ^ x * ln(2.0)");
+    assert_eq!(derivative.used_functions(), ["ln"]);
+    let printed = Expr::compile_env(derivative.to_string(), calculus_env()).unwrap();
+    assert_eq!(printed.ast(), derivative.ast());
+    let env = ExprEnv::<f64, 1>::new(["x"]);
+    assert!(matches!(
+        Expr::compile_env("2 ^ x", env).unwrap().derivative("x"),
+        Err(DerivativeError::NoDerivative(name)) if name == "ln"
+    ));
+
+    // Terms not depending on the variable vanish, even if they can't be differentiated
+    assert_eq!(d("floor(y) * x + 3", "x").to_string(), "floor(y)");
+    assert!(matches!(
+        Expr::compile_env("floor(x)", calculus_env()).unwrap().derivative("x"),
+        Err(DerivativeError::NoDerivative(name)) if name == "floor"
+    ));
+    assert!(matches!(
+        Expr::compile_env("x", calculus_env()).unwrap().derivative("z"),
+        Err(DerivativeError::UnknownVariable(name)) if name == "z"
+    ));
+
+    // Partial derivatives see the names of their namespace
+    let env = ExprEnv::<f64, 1>::new(["x"]).with_namespace("m", |m| m.with_trig());
+    let expr = Expr::compile_env("m.sin(x)", env).unwrap();
+    assert_eq!(expr.derivative("x").unwrap().to_string(), "m.cos(x)");
+
+    // Integers follow the integer mode, and `^` can only be differentiated by its base
+    let env = ExprEnv::new(["x"])
+        .with_integer_mode(IntegerMode::Wrapping)
+        .with_bitwise();
+    let expr = Expr::compile_env("x ^ 3 - 5 * x", env).unwrap();
+    let derivative = expr.derivative("x").unwrap();
+    assert_eq!(derivative.to_string(), "3 * x ^ 2 - 5");
+    assert_eq!(
+        derivative.evaluate(&[200u8]).unwrap(),
+        3u8.wrapping_mul(64).wrapping_sub(5)
+    );
+    let env = ExprEnv::<i32, 2>::new(["x", "y"]);
+    let derivative = Expr::compile_env("x ^ y", env)
+        .unwrap()
+        .derivative("x")
+        .unwrap();
+    assert_eq!(derivative.evaluate(&[2, 3]).unwrap(), 12);
+    assert_eq!(derivative.evaluate(&[2, 0]).unwrap(), 0);
+    let env = || ExprEnv::<i32, 1>::new(["x"]).with_bitwise();
+    let sources = [
+        ("2 ^ x", "^"),
+        ("x & 1", "&"),
+        ("~x", "~"),
+        ("x / 2", "/"),
+        ("7 % x", "%"),
+    ];
+    for (source, op) in sources {
+        let err = Expr::compile_env(source, env()).unwrap().derivative("x");
+        assert!(matches!(err, Err(DerivativeError::NotDifferentiable(o)) if o == op));
+    }
+
+    let expr = DynExpr::compile_env("a * b", DynEnv::new(vec!["a".into(), "b".into()])).unwrap();
+    assert_eq!(expr.derivative("b").unwrap().evaluate(&[7, 1]).unwrap(), 7);
+}
+
+#[test]
+fn gradient() {
+    let sources = [
+        "x * y + x / y - x % y",
+        "x ^ y + 2 ^ x - y ^ 3",
+        "-sin(x * y) + tan(x) * cos(y)",
+        "hypot(x, 2 * y) + floor(2.5)",
+        "x < y ? x * x : -x - y",
+    ];
+    for source in sources {
+        let expr = Expr::compile_env(source, calculus_env()).unwrap();
+        for vars in [[1.5, 2.0], [3.0, 0.5]] {
+            let (value, gradient) = expr.evaluate_with_gradient(&vars).unwrap();
+            assert_eq!(value, expr.evaluate(&vars).unwrap());
+            for (var, g) in ["x", "y"].into_iter().zip(gradient) {
+                let expected = expr.derivative(var).unwrap().evaluate(&vars).unwrap();
+                assert!((g - expected).abs() < 1e-9, "d({source})/d{var} was {g}");
+            }
+        }
+    }
+    // Like the symbolic derivative, a zero exponent gives a zero derivative by the base
+    let expr = Expr::compile_env("x ^ y", calculus_env()).unwrap();
+    let (_, [dx, _]) = expr.evaluate_with_gradient(&[0.0, 0.0]).unwrap();
+    assert_eq!(dx, 0.0);
+
+    // The dual form is built once per expression, and not carried over to new expressions
+    let expr = Expr::compile_env("x * x * y", calculus_env()).unwrap();
+    assert_eq!(
+        expr.evaluate_with_gradient(&[2.0, 3.0]).unwrap(),
+        (12.0, [12.0, 4.0])
+    );
+    assert_eq!(
+        expr.clone().evaluate_with_gradient(&[1.0, 1.0]).unwrap(),
+        (1.0, [2.0, 1.0])
+    );
+    let dx = expr.derivative("x").unwrap();
+    assert_eq!(
+        dx.evaluate_with_gradient(&[2.0, 3.0]).unwrap(),
+        (12.0, [6.0, 4.0])
+    );
+
+    let expr = Expr::compile_env("floor(x) * y", calculus_env()).unwrap();
+    for _ in 0..2 {
+        let err = expr.evaluate_with_gradient(&[1.0, 2.0]).unwrap_err();
+        assert!(matches!(
+            err,
+            EvalError::Derivative(DerivativeError::NoDerivative(_))
+        ));
+    }
+
+    // Dual numbers follow the float mode, checking their derivatives too
+    let env = ExprEnv::new(["x"]).with_float_mode(FloatMode::Strict);
+    let expr = Expr::compile_env("x ^ 0.5", env).unwrap();
+    assert_eq!(expr.evaluate(&[0.0]).unwrap(), 0.0);
+    let err = expr.evaluate_with_gradient(&[0.0]).unwrap_err();
+    assert!(matches!(err.root(), EvalError::NonFinite));
+}
+
+#[test]
+fn std_math_derivatives() {
+    let env = || ExprEnv::<f64, 2>::new(["x", "y"]).with_std_math();
+    let sources = [
+        "exp(x) + ln(x) + log10(x) + log2(x)",
+        "log(x, y) + log(y, x)",
+        "sqrt(x) + cbrt(x) + hypot(x, y)",
+        "sin(x) + cos(x) + tan(x)",
+        "asin(x / 4) + acos(x / 4) + atan(x) + atan2(x, y) + atan2(y, x)",
+        "sinh(x) + cosh(x) + tanh(x) + asinh(x) + acosh(x) + atanh(x / 4)",
+        "abs(x - y) + sign(x) + floor(x) + ceil(x) + round(x) + trunc(x)",
+        "clamp(x, 1, y) + clamp(0, x, y) + clamp(5, y, x + 2)",
+    ];
+    // Central differences at a point away from every step and edge
+    let (x, y, h) = (1.3, 2.7, 1e-6);
+    for source in sources {
+        let expr = Expr::compile_env(source, env()).unwrap();
+        let (_, gradient) = expr.evaluate_with_gradient(&[x, y]).unwrap();
+        let numeric = [
+            (expr.evaluate(&[x + h, y]).unwrap() - expr.evaluate(&[x - h, y]).unwrap()) / (2.0 * h),
+            (expr.evaluate(&[x, y + h]).unwrap() - expr.evaluate(&[x, y - h]).unwrap()) / (2.0 * h),
+        ];
+        for ((var, g), n) in ["x", "y"].into_iter().zip(gradient).zip(numeric) {
+            let d = expr.derivative(var).unwrap().evaluate(&[x, y]).unwrap();
+            assert!((d - n).abs() < 1e-6, "d({source})/d{var} was {d}, not {n}");
+            assert!(
+                (g - d).abs() < 1e-9,
+                "gradient of {source} by {var} was {g}, not {d}"
+            );
+        }
+    }
+    // Variadic functions can't be given derivatives
+    for source in ["min(x, y)", "max(x, 1)"] {
+        let err = Expr::compile_env(source, env()).unwrap().derivative("x");
+        assert!(matches!(err, Err(DerivativeError::NoDerivative(_))));
+    }
+}
+
+#[test]
+fn simplify() {
+    use crate::simplify::Strictness::{self, Exact, Relaxed};
+
+    fn check<T: Number>(
+        env: impl Fn() -> ExprEnv<T, 2>,
+        strictness: Strictness,
+        cases: &[(&str, &str)],
+    ) {
+        for (source, simplified) in cases {
+            let expr = Expr::compile_env(*source, env()).unwrap();
+            let expr = expr.simplify(strictness).unwrap();
+            assert_eq!(expr.to_string(), *simplified, "simplifying {source}");
+        }
+    }
+
+    let ints = || ExprEnv::<i32, 2>::new(["x", "y"]).with_min_max();
+    check(
+        ints,
+        Exact,
+        &[
+            ("x * 1 + 0 - 0", "x"),
+            ("1 * (x / 1) ^ 1", "x"),
+            ("0 * x + y ^ 0", "1"),
+            ("x - x + y", "y"),
+            ("(x + 2) + 3", "x + 5"),
+            ("3 + (2 + x) * 1", "x + 5"),
+            ("(x * 2) * 3", "x * 6"),
+            ("(1 + 2) * (y * 4)", "y * 12"),
+            ("- -x", "--x"),
+            ("(0 < 1) ? x + 0 : y", "x"),
+            // Dropping these could lose an overflow or division by zero
+            ("(x + 2) + -3", "x + 2 + -3"),
+            ("(x * 2) * -3", "x * 2 * -3"),
+            ("0 * (x / y)", "0 * (x / y)"),
+            ("max(x) - max(x)", "max(x) - max(x)"),
+        ],
+    );
+    check(
+        ints,
+        Relaxed,
+        &[
+            ("(x + 2) + -3", "x + -1"),
+            ("0 * (x / y)", "0"),
+            ("- -x", "x"),
+            ("(x * y) - (x * y)", "0"),
+        ],
+    );
+    let wrapping = || ExprEnv::<i32, 2>::new(["x", "y"]).with_integer_mode(IntegerMode::Wrapping);
+    check(
+        wrapping,
+        Exact,
+        &[("(x + 2) + -3", "x + -1"), ("- -x", "x")],
+    );
+    // Folding the constants would saturate, giving a different result for some `x`
+    let saturating =
+        || ExprEnv::<i32, 2>::new(["x", "y"]).with_integer_mode(IntegerMode::Saturating);
+    check(
+        saturating,
+        Exact,
+        &[
+            ("(x + 2) + 3", "x + 5"),
+            ("(x * 2) * 3", "x * 6"),
+            (
+                "(x + 2147483647) + 2147483647",
+                "x + 2147483647 + 2147483647",
+            ),
+            ("(x * 2147483647) * 2", "x * 2147483647 * 2"),
+        ],
+    );
+    for (source, x) in [
+        ("(x + 2147483647) + 2147483647", i32::MIN),
+        ("(x * 2147483647) * 2", -1),
+    ] {
+        let expr = Expr::compile_env(source, saturating()).unwrap();
+        let expected = expr.evaluate(&[x, 0]).unwrap();
+        let simplified = expr.simplify(Exact).unwrap();
+        assert_eq!(simplified.evaluate(&[x, 0]).unwrap(), expected);
+    }
+
+    let floats = || ExprEnv::<f64, 2>::new(["x", "y"]);
+    check(
+        floats,
+        Exact,
+        &[
+            ("x * 1 - 0", "x"),
+            ("y ^ 0 + (x / 1) ^ 1", "1.0 + x"),
+            ("- -x", "x"),
+            // -0 + 0 is 0, 0 * NaN is NaN, and floats round at each step
+            ("x + 0", "x + 0.0"),
+            ("0 * x", "0.0 * x"),
+            ("x - x", "x - x"),
+            ("(x + 2) + 3", "x + 2.0 + 3.0"),
+        ],
+    );
+    check(
+        floats,
+        Relaxed,
+        &[
+            ("x + 0", "x"),
+            ("0 * x + y - y", "0.0"),
+            ("(x + 2) + 3", "x + 5.0"),
+            ("2 * (x * 0.5)", "x"),
+        ],
+    );
+    // Under strict float mode variables are already known to be finite
+    let strict = || ExprEnv::<f64, 2>::new(["x", "y"]).with_float_mode(FloatMode::Strict);
+    check(
+        strict,
+        Exact,
+        &[
+            ("x * 1", "x"),
+            ("(x + y) * 1", "x + y"),
+            ("x + 0", "x + 0.0"),
+        ],
+    );
+
+    let expr = Expr::compile_env("(x - x) * 1 / 0", ints()).unwrap();
+    let simplified = expr.simplify(Exact).unwrap();
+    assert!(matches!(
+        simplified.evaluate(&[1, 2]).unwrap_err().root(),
+        EvalError::DivideByZero
+    ));
+    assert!(Expr::compile_env("1 / 0 + x", ints())
+        .unwrap()
+        .simplify(Relaxed)
+        .is_err());
+}
@@ -0,0 +1,61 @@
+use std::{collections::HashMap, hash::BuildHasher};
+
+use crate::EvalError;
+
+/// Derive [`VarSource`] for a struct, so that its fields are variables of the same name. Fields
+/// are cloned out and converted with [`Into`]. Requires the `derive` feature, which is on by
+/// default.
+///
+/// Example:
+/// ```
+/// use crunch_eval::{env::ExprEnv, expr::Expr, vars::ExprVars};
+///
+/// #[derive(ExprVars)]
+/// struct Player {
+///     health: f64,
+///     #[expr(rename = "lvl")]
+///     level: u8,
+///     #[expr(skip)]
+///     name: String,
+/// }
+///
+/// let player = Player { health: 80.0, level: 3, name: "Ada".into() };
+/// let env = ExprEnv::<f64, 2>::new(["health", "lvl"]);
+/// let expr = Expr::compile_env("health + lvl * 10", env).unwrap();
+/// assert_eq!(expr.evaluate_with(&player).unwrap(), 110.0);
+/// ```
+#[cfg(feature = "derive")]
+pub use crunch_eval_derive::ExprVars;
+
+/// A source of variable values looked up by name, such as a map or a struct deriving
+/// `ExprVars` with the `derive` feature
+pub trait VarSource<T> {
+    /// The value of the named variable, or `None` if there is no such variable
+    fn var(&self, name: &str) -> Option<T>;
+}
+
+impl<T: Copy, S: BuildHasher> VarSource<T> for HashMap<&str, T, S> {
+    fn var(&self, name: &str) -> Option<T> {
+        self.get(name).copied()
+    }
+}
+
+impl<T: Copy, S: BuildHasher> VarSource<T> for HashMap<String, T, S> {
+    fn var(&self, name: &str) -> Option<T> {
+        self.get(name).copied()
+    }
+}
+
+/// Fill `values` with the values of the variables of the same index in `names`
+pub(crate) fn lookup<T, S: VarSource<T> + ?Sized>(
+    source: &S,
+    names: &[String],
+    values: &mut [T],
+) -> Result<(), EvalError> {
+    for (value, name) in values.iter_mut().zip(names) {
+        *value = source
+            .var(name)
+            .ok_or_else(|| EvalError::MissingVariable(name.clone()))?;
+    }
+    Ok(())
+}